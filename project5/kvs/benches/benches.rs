@@ -3,7 +3,7 @@ use log::{warn};
 use env_logger::{Env};
 use criterion::{criterion_group, criterion_main, Criterion};
 use kvs::thread_pool::{RayonThreadPool};
-use kvs::{Client, Server, KvStore, KvsEngine};
+use kvs::{Client, Server, KvStore, KvOptions, KvsEngine};
 use tempfile::TempDir;
 use tokio::sync::{Barrier, oneshot};
 use std::time::Duration;
@@ -45,7 +45,7 @@ fn write_rayon_kvstore(c: &mut Criterion) {
                     let barrier = barrier.clone();
                     runtime.spawn(async move {
                         match Client::connect(addr.to_owned()).await {
-                            Ok(mut client) => {
+                            Ok(client) => {
                                 match client.set(format!("key{}", j), format!("value{}", j)).await {
                                     Ok(_) => {},
                                     Err(e) => warn!("client set error: {:?}", e)
@@ -111,7 +111,7 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                     let barrier = barrier.clone();
                     runtime.spawn(async move {
                         match Client::connect(addr.to_owned()).await {
-                            Ok(mut client) => {
+                            Ok(client) => {
                                 match client.get(format!("key{}", j)).await {
                                     Ok(value) => {
                                         assert_eq!(value, Some(format!("value{}", j)));
@@ -185,6 +185,43 @@ fn concurrent_set(c: &mut Criterion) {
     }
 }
 
+// same writes as `concurrent_set`, spread over a growing number of shards
+fn concurrent_set_sharded(c: &mut Criterion) {
+    START.call_once(|| {
+        env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    });
+
+    let mut group = c.benchmark_group("concurrent_set_sharded");
+    for i in &[1, 2, 4, 8, 16] {
+        group.bench_with_input(format!("shards_{}", i), i, |b, &i| {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                                .enable_all()
+                                .build()
+                                .unwrap();
+            let temp_dir = TempDir::new().unwrap();
+            let entries_len:usize = 10000;
+
+            let cpu_num = num_cpus::get();
+            let options = KvOptions { shards: i, ..KvOptions::default() };
+            let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), cpu_num, options).unwrap();
+            b.to_async(&runtime).iter(|| async {
+                let barrier = Arc::new(Barrier::new(entries_len + 1));
+                for j in 0..entries_len {
+                    let store = store.clone();
+                    let barrier = barrier.clone();
+                    runtime.spawn(async move {
+                        store
+                            .set(format!("key{}", j), format!("value{}", j)).await
+                            .unwrap();
+                        barrier.wait().await;
+                    });
+                }
+                barrier.wait().await;
+            });
+        });
+    }
+}
+
 fn concurrent_get(c: &mut Criterion) {
     START.call_once(|| {
         env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
@@ -234,5 +271,5 @@ criterion_group!(
     name = benches;
     config = Criterion::default().sample_size(10);
     // targets = write_queued_kvstore, write_rayon_kvstore, read_queued_kvstore, read_rayon_kvstore);
-    targets = concurrent_set);
+    targets = concurrent_set, concurrent_set_sharded);
 criterion_main!(benches);
\ No newline at end of file
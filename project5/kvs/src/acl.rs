@@ -0,0 +1,215 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::{BatchOp, KvError, Request, Result, DEFAULT_KEYSPACE};
+
+/// What a rule allows on the keys under its prefix. `Admin` is not about
+/// keys, it covers the stats and the creation and removal of the rule's
+/// keyspace, and only counts on a rule with an empty prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Admin => write!(f, "administer"),
+        }
+    }
+}
+
+/// Per-user access rules read from a JSON file like
+///
+/// ```json
+/// {
+///     "users": {
+///         "alice": [
+///             { "prefix": "team-a/", "ops": ["read", "write"] },
+///             { "keyspace": "archive", "prefix": "team-a/", "ops": ["read"] }
+///         ],
+///         "root": [{ "prefix": "", "ops": ["read", "write", "admin"] }]
+///     },
+///     "default": [{ "prefix": "public/", "ops": ["read"] }]
+/// }
+/// ```
+///
+/// A rule applies to one keyspace, the default one unless it names another.
+/// Users that aren't listed, including everyone who logged in with the
+/// shared secret, get the `default` rules. Whatever no rule allows is denied,
+/// listing the keyspaces takes `admin` on any of them.
+/// `reload` swaps in the file's current rules while the server is running.
+#[derive(Debug)]
+pub struct Acl {
+    path: PathBuf,
+    rules: RwLock<Arc<AclRules>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AclRules {
+    #[serde(default)]
+    users: HashMap<String, Vec<Rule>>,
+    #[serde(default)]
+    default: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    #[serde(default = "default_keyspace")]
+    keyspace: String,
+    prefix: String,
+    ops: Vec<Permission>,
+}
+
+fn default_keyspace() -> String {
+    DEFAULT_KEYSPACE.to_owned()
+}
+
+impl Rule {
+    /// whether this rule grants `admin` on its whole keyspace
+    fn administers(&self) -> bool {
+        self.prefix.is_empty() && self.ops.contains(&Permission::Admin)
+    }
+}
+
+impl Acl {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let rules = load_rules(&path)?;
+        Ok(Acl { path, rules: RwLock::new(Arc::new(rules)) })
+    }
+
+    /// read the file again, the old rules stay if it's broken
+    pub fn reload(&self) -> Result<()> {
+        let rules = load_rules(&self.path)?;
+        *self.rules.write().unwrap() = Arc::new(rules);
+        Ok(())
+    }
+
+    /// Whether `user` may send `req`, `None` being a user without a name.
+    pub(crate) fn check(&self, user: Option<&str>, req: &Request) -> Result<()> {
+        let rules = self.rules.read().unwrap().clone();
+        let all_rules = user.and_then(|user| rules.users.get(user)).unwrap_or(&rules.default);
+        let space = keyspace(req);
+        let rules: Vec<&Rule> = all_rules.iter().filter(|rule| rule.keyspace == space).collect();
+        let allows = |op: Permission, key: &str| {
+            rules.iter().any(|rule| rule.ops.contains(&op) && key.starts_with(&rule.prefix))
+        };
+        let denied = |op: Permission, resource: String| KvError::PermissionDenied {
+            user: user.unwrap_or("<anonymous>").to_owned(),
+            op,
+            resource: if space == DEFAULT_KEYSPACE { resource } else { format!("{} in keyspace {:?}", resource, space) },
+        };
+        let key = |op: Permission, key: &String| {
+            if allows(op, key) { Ok(()) } else { Err(denied(op, format!("key {:?}", key))) }
+        };
+        let admin = |what: &str| {
+            if rules.iter().any(|rule| rule.administers()) {
+                Ok(())
+            } else {
+                Err(denied(Permission::Admin, what.to_owned()))
+            }
+        };
+
+        match req {
+            Request::Get { key: k, .. } => key(Permission::Read, k),
+            Request::MGet { keys, .. } => keys.iter().try_for_each(|k| key(Permission::Read, k)),
+            Request::Set { key: k, .. }
+            | Request::Remove { key: k, .. }
+            | Request::Cas { key: k, .. }
+            | Request::Incr { key: k, .. }
+            | Request::Append { key: k, .. } => key(Permission::Write, k),
+            Request::MSet { pairs, .. } => pairs.iter().try_for_each(|(k, _)| key(Permission::Write, k)),
+            Request::Batch { ops, .. } => ops.iter().try_for_each(|op| match op {
+                BatchOp::Set { key: k, .. } | BatchOp::Remove { key: k } => key(Permission::Write, k),
+            }),
+            // every key in the range has to be readable
+            Request::Scan { start, end, .. } => {
+                let covered = rules.iter().any(|rule| {
+                    rule.ops.contains(&Permission::Read) && range_within(start, end.as_deref(), &rule.prefix)
+                });
+                if covered {
+                    Ok(())
+                } else {
+                    Err(denied(Permission::Read, format!("range {:?}..{:?}", start, end.as_deref().unwrap_or(""))))
+                }
+            },
+            Request::Watch { prefix, .. } => {
+                if allows(Permission::Read, prefix) {
+                    Ok(())
+                } else {
+                    Err(denied(Permission::Read, format!("prefix {:?}", prefix)))
+                }
+            },
+            Request::Stats { .. } => admin("stats"),
+            Request::CreateKeyspace { .. } | Request::DropKeyspace { .. } => admin("the keyspace"),
+            Request::ListKeyspaces => {
+                if all_rules.iter().any(|rule| rule.administers()) {
+                    Ok(())
+                } else {
+                    Err(denied(Permission::Admin, "keyspaces".to_owned()))
+                }
+            },
+            Request::Ping | Request::Auth { .. } => Ok(()),
+        }
+    }
+}
+
+fn load_rules(path: &Path) -> Result<AclRules> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// the keyspace `req` touches, for creating and dropping the one it names
+fn keyspace(req: &Request) -> &str {
+    let name = match req {
+        Request::Get { keyspace, .. }
+        | Request::Set { keyspace, .. }
+        | Request::Remove { keyspace, .. }
+        | Request::Stats { keyspace }
+        | Request::Watch { keyspace, .. }
+        | Request::Batch { keyspace, .. }
+        | Request::Cas { keyspace, .. }
+        | Request::Scan { keyspace, .. }
+        | Request::Incr { keyspace, .. }
+        | Request::Append { keyspace, .. }
+        | Request::MGet { keyspace, .. }
+        | Request::MSet { keyspace, .. } => keyspace.as_deref(),
+        Request::CreateKeyspace { name } | Request::DropKeyspace { name } => Some(name.as_str()),
+        Request::ListKeyspaces | Request::Ping | Request::Auth { .. } => None,
+    };
+    name.unwrap_or(DEFAULT_KEYSPACE)
+}
+
+/// whether all keys in `start..end` start with `prefix`
+fn range_within(start: &str, end: Option<&str>, prefix: &str) -> bool {
+    if !start.starts_with(prefix) {
+        return false;
+    }
+    match (end, prefix_end(prefix)) {
+        (Some(end), Some(limit)) => end.starts_with(prefix) || end <= limit.as_str(),
+        (None, Some(_)) => false,
+        // nothing sorts after the prefix
+        (_, None) => true,
+    }
+}
+
+/// the smallest string greater than every string starting with `prefix`
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(c) = chars.pop() {
+        if let Some(next) = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
@@ -0,0 +1,93 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::{KvError, Result};
+
+/// Checks the passwords of connecting clients against the hashes of an auth
+/// file, a JSON document like
+///
+/// ```json
+/// {
+///     "shared_secret_hash": "$argon2id$v=19$...",
+///     "users": { "alice": { "password_hash": "$argon2id$v=19$..." } }
+/// }
+/// ```
+///
+/// Both parts are optional. Hashes are PHC strings as made by
+/// `hash_password` or the `argon2` command line tool.
+#[derive(Debug)]
+pub struct Authenticator {
+    config: AuthConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthConfig {
+    // accepted for any user name not listed in `users`
+    #[serde(default)]
+    shared_secret_hash: Option<String>,
+    #[serde(default)]
+    users: HashMap<String, UserConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserConfig {
+    password_hash: String,
+}
+
+impl Authenticator {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let config: AuthConfig = serde_json::from_slice(&fs::read(path.as_ref())?)?;
+        // catch broken hashes at startup rather than on the first login
+        for hash in config.shared_secret_hash.iter().chain(config.users.values().map(|user| &user.password_hash)) {
+            PasswordHash::new(hash).map_err(|e| KvError::StringError(format!("invalid password hash: {}", e)))?;
+        }
+        Ok(Authenticator { config })
+    }
+
+    /// Check `password` against the one of `user`, or the shared secret for
+    /// users without their own. Returns who logged in, `None` for the shared
+    /// secret. Slow on purpose, run it off the runtime.
+    pub(crate) fn verify(&self, user: Option<&str>, password: &str) -> Result<Option<String>> {
+        let (hash, name) = match user.filter(|user| self.config.users.contains_key(*user)) {
+            Some(user) => (&self.config.users[user].password_hash, Some(user.to_owned())),
+            None => match &self.config.shared_secret_hash {
+                Some(hash) => (hash, None),
+                None => return Err(KvError::AuthFailed),
+            },
+        };
+        let hash = PasswordHash::new(hash).map_err(|_| KvError::AuthFailed)?;
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(name),
+            Err(_) => Err(KvError::AuthFailed),
+        }
+    }
+}
+
+/// an argon2id hash of `password` with a random salt, for the auth file
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| KvError::StringError(e.to_string()))
+}
+
+/// a user name and password, the password is left out of `Debug`
+#[derive(Clone)]
+pub struct Credentials {
+    /// `None` to log in with the shared secret
+    pub user: Option<String>,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials").field("user", &self.user).finish_non_exhaustive()
+    }
+}
@@ -1,7 +1,9 @@
 extern crate tokio;
 
-use kvs::{Client, Result};
-use std::{env, process};
+use kvs::{Client, ClientTls, ConnectOptions, Credentials, KvError, Result};
+use futures::TryStreamExt;
+use std::{env, fs, process};
+use std::path::PathBuf;
 use structopt::StructOpt;
 use env_logger::{Env};
 use log::{info};
@@ -11,34 +13,181 @@ use log::{info};
 pub struct Opt {
     #[structopt(subcommand)]
     cmd: Option<Cmd>,
+
+    #[structopt(flatten)]
+    conn: ConnOpt,
+}
+
+/// how to reach the server, shared by all subcommands
+#[derive(StructOpt, Debug, PartialEq)]
+pub struct ConnOpt {
+    // connect over TLS, trusting the server certificates signed by these CAs
+    #[structopt(name="tls-ca", long, global=true, parse(from_os_str), about="[--tls-ca PATH]")]
+    tls_ca: Option<PathBuf>,
+
+    // the name on the server's certificate, the host of --addr by default
+    #[structopt(name="tls-server-name", long, global=true, about="[--tls-server-name NAME]")]
+    tls_server_name: Option<String>,
+
+    // presented to servers that verify clients
+    #[structopt(name="tls-cert", long, global=true, parse(from_os_str), about="[--tls-cert PATH]")]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(name="tls-key", long, global=true, parse(from_os_str), about="[--tls-key PATH]")]
+    tls_key: Option<PathBuf>,
+
+    // connect to the server's Unix socket at this path instead of --addr
+    #[structopt(name="unix", long, global=true, parse(from_os_str), about="[--unix PATH]")]
+    unix: Option<PathBuf>,
+
+    // log in as this user, the shared secret is used without one
+    #[structopt(name="user", long, global=true, requires="password-file", about="[--user NAME]")]
+    user: Option<String>,
+
+    // file holding the password, kept off the command line and out of `ps`
+    #[structopt(name="password-file", long, global=true, parse(from_os_str), about="[--password-file PATH]")]
+    password_file: Option<PathBuf>,
 }
 
 #[derive(StructOpt, Debug, PartialEq)]
 pub enum Cmd {
-    #[structopt(name="get", about="get <key> [--addr IP-PORT]")]
+    #[structopt(name="get", about="get <key> [--addr IP-PORT] [--keyspace NAME]")]
     Get { 
         key: String, 
 
         // 不加long参数 addr是一个args
         #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
         addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
     },
 
-    #[structopt(name="set", about="set <key> <value> [--addr IP-PORT]")]
+    #[structopt(name="set", about="set <key> <value> [--addr IP-PORT] [--keyspace NAME]")]
     Set { 
         key: String, 
         value: String,
 
         #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
         addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
     },
 
-    #[structopt(name="rm", about="rm <key> [--addr IP-PORT]")]
+    #[structopt(name="rm", about="rm <key> [--addr IP-PORT] [--keyspace NAME]")]
     Rm { 
         key: String,
 
         #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
         addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="mget", about="mget <key>... [--addr IP-PORT] [--keyspace NAME]")]
+    MGet {
+        #[structopt(required = true)]
+        keys: Vec<String>,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="mset", about="mset <key> <value> [<key> <value>]... [--addr IP-PORT] [--keyspace NAME]")]
+    MSet {
+        #[structopt(required = true)]
+        pairs: Vec<String>,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="incr", about="incr <key> [delta] [--addr IP-PORT] [--keyspace NAME]")]
+    Incr {
+        key: String,
+        #[structopt(default_value="1")]
+        delta: i64,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="decr", about="decr <key> [delta] [--addr IP-PORT] [--keyspace NAME]")]
+    Decr {
+        key: String,
+        #[structopt(default_value="1")]
+        delta: i64,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="append", about="append <key> <suffix> [--addr IP-PORT] [--keyspace NAME]")]
+    Append {
+        key: String,
+        suffix: String,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="watch", about="watch <prefix> [--addr IP-PORT] [--keyspace NAME]")]
+    Watch {
+        prefix: String,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="stats", about="stats [--addr IP-PORT] [--keyspace NAME]")]
+    Stats {
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+
+        #[structopt(name="keyspace", long)]
+        keyspace: Option<String>,
+    },
+
+    #[structopt(name="create-keyspace", about="create-keyspace <name> [--addr IP-PORT]")]
+    CreateKeyspace {
+        name: String,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+    },
+
+    #[structopt(name="drop-keyspace", about="drop-keyspace <name> [--addr IP-PORT]")]
+    DropKeyspace {
+        name: String,
+
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
+    },
+
+    #[structopt(name="keyspaces", about="keyspaces [--addr IP-PORT]")]
+    Keyspaces {
+        #[structopt(name="addr", long, default_value="127.0.0.1:4000")]
+        addr: String,
     },
 }
 
@@ -53,29 +202,117 @@ async fn main() -> Result<()> {
         process::exit(1);
     }
 
+    let conn = opt.conn;
     if let Some(command) = opt.cmd {
         match command {
-            Cmd::Get { key, addr } => {
+            Cmd::Get { key, addr, keyspace } => {
                 // info!("key: {}, addr: {}", key, addr);
-                let mut client = Client::connect(addr).await?;
+                let client = connect(addr, keyspace, &conn).await?;
                 if let Some(value) = client.get(key).await? {
                     println!("{}", value);
                 } else {
                     println!("Key not found");
                 }
             },
-            Cmd::Set { key, value, addr } => {
+            Cmd::Set { key, value, addr, keyspace } => {
                 // info!("key: {}, value: {}, addr: {}", key, value, addr);
-                let mut client = Client::connect(addr).await?;
+                let client = connect(addr, keyspace, &conn).await?;
                 client.set(key, value).await?;
             },
-            Cmd::Rm { key , addr} => {
+            Cmd::Rm { key , addr, keyspace } => {
                 // info!("key: {}, addr: {}", key, addr);
-                let mut client = Client::connect(addr).await?;
+                let client = connect(addr, keyspace, &conn).await?;
                 client.remove(key).await?;
+            },
+            Cmd::MGet { keys, addr, keyspace } => {
+                let client = connect(addr, keyspace, &conn).await?;
+                for value in client.get_many(keys).await? {
+                    match value {
+                        Some(value) => println!("{}", value),
+                        None => println!("Key not found"),
+                    }
+                }
+            },
+            Cmd::MSet { pairs, addr, keyspace } => {
+                if pairs.len() % 2 != 0 {
+                    return Err(KvError::StringError("mset takes key value pairs".to_owned()));
+                }
+                let pairs = pairs.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+                let client = connect(addr, keyspace, &conn).await?;
+                client.set_many(pairs).await?;
+            },
+            Cmd::Incr { key, delta, addr, keyspace } => {
+                let client = connect(addr, keyspace, &conn).await?;
+                println!("{}", client.incr(key, delta).await?);
+            },
+            Cmd::Decr { key, delta, addr, keyspace } => {
+                let client = connect(addr, keyspace, &conn).await?;
+                let delta = delta.checked_neg().ok_or(KvError::Overflow)?;
+                println!("{}", client.incr(key, delta).await?);
+            },
+            Cmd::Append { key, suffix, addr, keyspace } => {
+                let client = connect(addr, keyspace, &conn).await?;
+                println!("{}", client.append(key, suffix).await?);
+            },
+            Cmd::Watch { prefix, addr, keyspace } => {
+                let client = connect(addr, keyspace, &conn).await?;
+                let mut events = client.watch(prefix).await?;
+                while let Some(event) = events.try_next().await? {
+                    println!("{}", event);
+                }
+            },
+            Cmd::Stats { addr, keyspace } => {
+                let client = connect(addr, keyspace, &conn).await?;
+                println!("{}", client.stats().await?);
+            },
+            Cmd::CreateKeyspace { name, addr } => {
+                let client = connect(addr, None, &conn).await?;
+                client.create_keyspace(name).await?;
+            },
+            Cmd::DropKeyspace { name, addr } => {
+                let client = connect(addr, None, &conn).await?;
+                client.drop_keyspace(name).await?;
+            },
+            Cmd::Keyspaces { addr } => {
+                let client = connect(addr, None, &conn).await?;
+                for name in client.list_keyspaces().await? {
+                    println!("{}", name);
+                }
             }
         }
     }
 
     Ok(())
 }
+
+async fn connect(addr: String, keyspace: Option<String>, conn: &ConnOpt) -> Result<Client> {
+    let tls = conn.tls_ca.as_ref().map(|ca_file| ClientTls {
+        ca_file: ca_file.clone(),
+        server_name: conn.tls_server_name.clone().unwrap_or_else(|| host(&addr).to_owned()),
+        cert_file: conn.tls_cert.clone(),
+        key_file: conn.tls_key.clone(),
+    });
+    let credentials = match &conn.password_file {
+        Some(path) => Some(Credentials {
+            user: conn.user.clone(),
+            // editors tend to leave a newline at the end
+            password: fs::read_to_string(path)?.trim_end_matches(['\r', '\n']).to_owned(),
+        }),
+        None => None,
+    };
+    let options = ConnectOptions { tls, credentials, ..Default::default() };
+    let mut client = match &conn.unix {
+        Some(path) => Client::connect_unix_with(path, options).await?,
+        None => Client::connect_with(addr, options).await?,
+    };
+    if let Some(name) = keyspace {
+        client.use_keyspace(name);
+    }
+    Ok(client)
+}
+
+/// `addr` without the port
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
@@ -1,10 +1,13 @@
 extern crate tokio;
-use kvs::{KvStore, SledEngine, KvsEngine, Result, KvError, Server, thread_pool::RayonThreadPool};
+use kvs::{KvStore, KvOptions, Acl, Authenticator, Cipher, Codec, ServerTls, SledEngine, SledOptions, SledMode, LsmEngine, MemEngine, EvictionPolicy, KvsEngine, Result, KvError, Server, RespServer, HttpServer, MemcacheServer, thread_pool::RayonThreadPool};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::oneshot;
 use std::{fs, env};
 use structopt::StructOpt;
-use log::{info};
+use log::{error, info};
 use env_logger::{Env};
+use std::path::PathBuf;
+use std::time::Duration;
 use std::sync::{Arc, atomic::{AtomicBool}};
 
 #[derive(StructOpt, Debug, PartialEq)]
@@ -17,6 +20,141 @@ pub struct Opt {
     // 不加long参数 addr是一个args
     #[structopt(name="engine", long, default_value="kvs", about="[--engine ENGINE-NAME]")]
     engine: String,
+
+    // only used by the memory engine, unbounded if not set
+    #[structopt(name="max-memory", long, about="[--max-memory BYTES]")]
+    max_memory: Option<u64>,
+
+    #[structopt(name="eviction", long, default_value="lru", about="[--eviction lru|lfu]")]
+    eviction: EvictionPolicy,
+
+    // sled tuning, sled's defaults if not set
+    #[structopt(name="sled-cache-capacity", long, about="[--sled-cache-capacity BYTES]")]
+    sled_cache_capacity: Option<u64>,
+
+    // flush in the background instead of after every write
+    #[structopt(name="sled-flush-every-ms", long, about="[--sled-flush-every-ms MS]")]
+    sled_flush_every_ms: Option<u64>,
+
+    #[structopt(name="sled-compression", long, about="[--sled-compression LEVEL]")]
+    sled_compression: Option<i32>,
+
+    #[structopt(name="sled-mode", long, about="[--sled-mode low-space|high-throughput]")]
+    sled_mode: Option<SledMode>,
+
+    // only used by the kvs engine, must match the count the store was created with
+    #[structopt(name="shards", long, default_value="1", about="[--shards N]")]
+    shards: usize,
+
+    // only used by the kvs engine, log files with more dead bytes than this get compacted
+    #[structopt(name="garbage-ratio", long, default_value="0.5", about="[--garbage-ratio 0..1]")]
+    garbage_ratio: f64,
+
+    // also serve the native protocol on a Unix socket at this path
+    #[structopt(name="unix", long, parse(from_os_str), about="[--unix PATH]")]
+    unix: Option<PathBuf>,
+
+    // octal permissions of the Unix socket, who may connect
+    #[structopt(name="unix-mode", long, default_value="660", parse(try_from_str=parse_mode), about="[--unix-mode OCTAL]")]
+    unix_mode: u32,
+
+    // only listen on the Unix socket
+    #[structopt(name="no-tcp", long, requires="unix", about="[--no-tcp]")]
+    no_tcp: bool,
+
+    // also serve redis clients over RESP2 on this address
+    #[structopt(name="resp-addr", long, about="[--resp-addr IP-PORT]")]
+    resp_addr: Option<String>,
+
+    // also serve a JSON gateway over HTTP on this address
+    #[structopt(name="http-addr", long, about="[--http-addr IP-PORT]")]
+    http_addr: Option<String>,
+
+    // larger HTTP request bodies are refused
+    #[structopt(name="http-max-body", long, default_value="1048576", about="[--http-max-body BYTES]")]
+    http_max_body: usize,
+
+    // also serve memcached clients over its text protocol on this address
+    #[structopt(name="memcache-addr", long, about="[--memcache-addr IP-PORT]")]
+    memcache_addr: Option<String>,
+
+    // wire format for clients that don't pick one in the hello
+    #[structopt(name="codec", long, default_value="bincode", about="[--codec bincode|json|msgpack]")]
+    codec: Codec,
+
+    // serve the main listener over TLS with this certificate chain and key
+    #[structopt(name="tls-cert", long, parse(from_os_str), requires="tls-key", about="[--tls-cert PATH]")]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(name="tls-key", long, parse(from_os_str), requires="tls-cert", about="[--tls-key PATH]")]
+    tls_key: Option<PathBuf>,
+
+    // only accept clients with a certificate signed by these CAs
+    #[structopt(name="tls-client-ca", long, parse(from_os_str), requires="tls-cert", about="[--tls-client-ca PATH]")]
+    tls_client_ca: Option<PathBuf>,
+
+    // JSON file with the shared secret and user password hashes, clients of
+    // the main listener have to log in if given. The other protocols can't
+    // log in, so their listeners are refused
+    #[structopt(name="auth-file", long, parse(from_os_str), conflicts_with_all=&["resp-addr", "http-addr", "memcache-addr"], about="[--auth-file PATH]")]
+    auth_file: Option<PathBuf>,
+
+    // JSON file with the key prefixes and operations each user is allowed,
+    // read again on SIGHUP. Like the auth file, only for the main listener
+    #[structopt(name="acl-file", long, parse(from_os_str), conflicts_with_all=&["resp-addr", "http-addr", "memcache-addr"], about="[--acl-file PATH]")]
+    acl_file: Option<PathBuf>,
+
+    // connections without a request for this many seconds are closed, 0 keeps them open
+    #[structopt(name="idle-timeout", long, default_value="300", about="[--idle-timeout SECS]")]
+    idle_timeout: u64,
+
+    // only used by the kvs engine, bytes per second compaction may use, unlimited by default
+    #[structopt(name="compaction-rate", long, about="[--compaction-rate BYTES_PER_SEC]")]
+    compaction_rate: Option<u64>,
+
+    // only used by the kvs engine, the key is taken from `KEY_ENV` if no file is given
+    #[structopt(name="key-file", long, parse(from_os_str), about="[--key-file PATH]")]
+    key_file: Option<PathBuf>,
+
+    // keys being rotated away from, files under them are rewritten on startup
+    #[structopt(name="old-key-file", long, parse(from_os_str), about="[--old-key-file PATH]...")]
+    old_key_files: Vec<PathBuf>,
+}
+
+// hex encoded encryption key for the kvs engine
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+/// the cipher for the kvs engine's log files, `None` if no key is configured
+fn load_cipher(opt: &Opt) -> Result<Option<Cipher>> {
+    let cipher = match &opt.key_file {
+        Some(path) => Cipher::from_keyfile(path)?,
+        None if env::var_os(KEY_ENV).is_some() => Cipher::from_env(KEY_ENV)?,
+        None => return Ok(None),
+    };
+    opt.old_key_files.iter()
+        .try_fold(cipher, |cipher, path| Ok(cipher.with_old(Cipher::from_keyfile(path)?)))
+        .map(Some)
+}
+
+fn parse_mode(s: &str) -> std::result::Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s, 8)
+}
+
+/// read the ACL file again whenever the process gets a SIGHUP
+async fn reload_on_hangup(acl: Arc<Acl>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("can't reload the ACL on SIGHUP: {}", e);
+            return;
+        },
+    };
+    while hangups.recv().await.is_some() {
+        match acl.reload() {
+            Ok(()) => info!("reloaded the ACL"),
+            Err(e) => error!("keeping the old ACL, reloading failed: {}", e),
+        }
+    }
 }
 
 fn current_engine() -> Result<Option<String>> {
@@ -30,11 +168,83 @@ fn current_engine() -> Result<Option<String>> {
 }
 
 
-async fn run_with_engine<E: KvsEngine>(engine: E, addr: String) -> Result<()> {
+async fn run_with_engine<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
     let is_stop = Arc::new(AtomicBool::new(false));
-    let mut server = Server::new(engine, is_stop)?;
-    let (tx, rx) = oneshot::channel();
-    server.run(addr, rx).await?;
+    let mut server = Server::new(engine.clone(), is_stop)?.with_codec(opt.codec);
+    if let (Some(cert_file), Some(key_file)) = (&opt.tls_cert, &opt.tls_key) {
+        let tls = ServerTls {
+            cert_file: cert_file.clone(),
+            key_file: key_file.clone(),
+            client_ca_file: opt.tls_client_ca.clone(),
+        };
+        server = server.with_tls(&tls)?;
+    }
+    if let Some(path) = &opt.auth_file {
+        server = server.with_auth(Authenticator::from_file(path)?);
+    }
+    if let Some(path) = &opt.acl_file {
+        let acl = Arc::new(Acl::from_file(path)?);
+        server = server.with_acl(acl.clone());
+        tokio::spawn(reload_on_hangup(acl));
+    }
+    if opt.idle_timeout > 0 {
+        server = server.with_idle_timeout(Duration::from_secs(opt.idle_timeout));
+    }
+    let (_stop, rx) = oneshot::channel();
+    let tcp = async {
+        if opt.no_tcp {
+            Ok(())
+        } else {
+            server.clone().run(opt.addr.as_str(), rx).await
+        }
+    };
+
+    let (_unix_stop, unix_rx) = oneshot::channel();
+    let unix = async {
+        match &opt.unix {
+            Some(path) => {
+                info!("Unix socket listener on {}", path.display());
+                server.clone().run_unix(path, opt.unix_mode, unix_rx).await
+            },
+            None => Ok(()),
+        }
+    };
+
+    // the optional listeners share the engine
+    let (_resp_stop, resp_rx) = oneshot::channel();
+    let resp = async {
+        match &opt.resp_addr {
+            Some(addr) => {
+                info!("RESP listener on {}", addr);
+                RespServer::new(engine.clone()).run(addr.as_str(), resp_rx).await
+            },
+            None => Ok(()),
+        }
+    };
+
+    let (_http_stop, http_rx) = oneshot::channel();
+    let http = async {
+        match &opt.http_addr {
+            Some(addr) => {
+                info!("HTTP listener on {}", addr);
+                HttpServer::new(engine.clone()).with_max_body(opt.http_max_body).run(addr.as_str(), http_rx).await
+            },
+            None => Ok(()),
+        }
+    };
+
+    let (_memcache_stop, memcache_rx) = oneshot::channel();
+    let memcache = async {
+        match &opt.memcache_addr {
+            Some(addr) => {
+                info!("memcached listener on {}", addr);
+                MemcacheServer::new(engine.clone()).run(addr.as_str(), memcache_rx).await
+            },
+            None => Ok(()),
+        }
+    };
+
+    tokio::try_join!(tcp, unix, resp, http, memcache)?;
     Ok(())
 }
 
@@ -50,14 +260,21 @@ async fn main() -> Result<()> {
     let opt = Opt::from_args();
     // println!("args: {:?}", opt);
 
-    let addr = opt.addr;
-    let engine = opt.engine;
+    let cipher = load_cipher(&opt)?;
+    let addr = opt.addr.clone();
+    let engine = opt.engine.clone();
 
     info!("{} vesrsion {}, addr: {} port: {}", 
             env!("CARGO_PKG_NAME"), 
             env!("CARGO_PKG_VERSION"), 
             addr, engine);
 
+    // nothing is persisted, so the memory engine doesn't claim the directory
+    if engine == "memory" {
+        run_with_engine(MemEngine::with_limit(opt.max_memory, opt.eviction), &opt).await?;
+        return Ok(());
+    }
+
     if let Some(curr_engine) = current_engine()? {
         if curr_engine != engine {
             return Err(KvError::WrongEngine);
@@ -70,9 +287,23 @@ async fn main() -> Result<()> {
     let cpu_num = num_cpus::get();
 
     if engine == "kvs" {
-        run_with_engine(KvStore::<RayonThreadPool>::open(env::current_dir()?, cpu_num)?, addr).await?;
+        let options = KvOptions {
+            shards: opt.shards,
+            cipher,
+            garbage_ratio: opt.garbage_ratio,
+            compaction_rate: opt.compaction_rate,
+        };
+        run_with_engine(KvStore::<RayonThreadPool>::open_with(env::current_dir()?, cpu_num, options)?, &opt).await?;
     } else if engine == "sled" {
-        run_with_engine(SledEngine::<RayonThreadPool>::open(env::current_dir()?, cpu_num)?, addr).await?;
+        let options = SledOptions {
+            cache_capacity: opt.sled_cache_capacity,
+            flush_every_ms: opt.sled_flush_every_ms,
+            compression: opt.sled_compression,
+            mode: opt.sled_mode,
+        };
+        run_with_engine(SledEngine::<RayonThreadPool>::open_with(env::current_dir()?, cpu_num, options)?, &opt).await?;
+    } else if engine == "lsm" {
+        run_with_engine(LsmEngine::<RayonThreadPool>::open(env::current_dir()?, cpu_num)?, &opt).await?;
     } else {
         return Err(KvError::WrongEngine);
     }
@@ -1,42 +1,171 @@
-use crate::{KvError, Result};
-use crate::common::{Request, Response};
+use crate::{BatchOp, ClientTls, Codec, Credentials, KvError, EngineStats, WatchStream, WireFormat, Result};
+use crate::common::{Request, Response, Tagged, Hello, HelloReply, Capabilities, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::ToSocketAddrs;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
+use std::path::Path;
 use tokio_serde::formats::*;
 use tokio_serde::SymmetricallyFramed;
 use futures::prelude::*;
+use log::debug;
+
+/// read half of a plain or TLS connection
+pub type ConnReader = Box<dyn AsyncRead + Send + Unpin>;
+/// write half of a plain or TLS connection
+pub type ConnWriter = Box<dyn AsyncWrite + Send + Unpin>;
 
 pub type SymmetricalReader<T> = SymmetricallyFramed<
-    FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    FramedRead<ConnReader, LengthDelimitedCodec>,
     T,
-    SymmetricalBincode<T>>;
+    WireFormat<T>>;
 
 pub type SymmetricalWriter<T> = SymmetricallyFramed<
-    FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+    FramedWrite<ConnWriter, LengthDelimitedCodec>,
     T,
-    SymmetricalBincode<T>>;
+    WireFormat<T>>;
+
+/// split any connection into boxed halves
+pub(crate) fn split_stream<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> (ConnReader, ConnWriter) {
+    let (reader, writer) = tokio::io::split(stream);
+    (Box::new(reader), Box::new(writer))
+}
+
+/// options for `Client::connect_with`
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// serialization of requests and responses, the server's default if `None`
+    pub codec: Option<Codec>,
+    /// plain TCP if `None`
+    pub tls: Option<ClientTls>,
+    /// logged in with right after the hello if set
+    pub credentials: Option<Credentials>,
+}
 
+/// A connection to a kvs-server that stays open for any number of requests.
+///
+/// Requests are tagged with an id and don't wait for each other, any number
+/// of calls can be in flight at once. The server may run them concurrently,
+/// so only a completed call is guaranteed to be visible to the next one.
 pub struct Client {
-    reader: SymmetricalReader<Response>,
-    writer: SymmetricalWriter<Request>,
+    writer: tokio::sync::Mutex<SymmetricalWriter<Tagged<Request>>>,
+    // callers waiting for a response, `None` once the connection is gone
+    waiters: Arc<Mutex<Option<HashMap<u64, Waiter>>>>,
+    next_id: AtomicU64,
+    // hands responses to the waiters
+    reader_task: JoinHandle<()>,
+    // keyspace sent along with every request, the default one if `None`
+    keyspace: Option<String>,
+    // agreed on in the hello
+    version: u32,
+    capabilities: Capabilities,
+    codec: Codec,
+}
+
+enum Waiter {
+    // a single response
+    Once(oneshot::Sender<Response>),
+    // a watch, answered over and over
+    Stream(mpsc::UnboundedSender<Response>),
 }
 
 impl Client {
+    /// connect and agree on a protocol version and capabilities with the server
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        let (read_half, write_half) = stream.into_split();
-        let reader = SymmetricallyFramed::new(FramedRead::new(read_half, LengthDelimitedCodec::new()), SymmetricalBincode::default());
-        let writer = SymmetricallyFramed::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()), SymmetricalBincode::default());
-        Ok(Client {
-            reader,
-            writer,
-        })
+        Client::connect_with(addr, ConnectOptions::default()).await
+    }
+
+    pub async fn connect_with<A: ToSocketAddrs>(addr: A, options: ConnectOptions) -> Result<Self> {
+        Client::establish(TcpStream::connect(addr).await?, options).await
+    }
+
+    /// connect to a server listening on the Unix socket at `path`
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Client::connect_unix_with(path, ConnectOptions::default()).await
+    }
+
+    pub async fn connect_unix_with<P: AsRef<Path>>(path: P, options: ConnectOptions) -> Result<Self> {
+        Client::establish(UnixStream::connect(path).await?, options).await
+    }
+
+    /// hello and login over a fresh connection
+    async fn establish<S>(stream: S, options: ConnectOptions) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (read_half, write_half) = match &options.tls {
+            Some(tls) => {
+                let (connector, server_name) = tls.connector()?;
+                split_stream(connector.connect(server_name, stream).await?)
+            },
+            None => split_stream(stream),
+        };
+        let mut frames_in = FramedRead::new(read_half, LengthDelimitedCodec::new());
+        let mut frames_out = FramedWrite::new(write_half, LengthDelimitedCodec::new());
+        let (version, capabilities, codec) = handshake(&mut frames_in, &mut frames_out, options.codec).await?;
+        let reader = SymmetricallyFramed::new(frames_in, WireFormat::new(codec));
+        let writer = SymmetricallyFramed::new(frames_out, WireFormat::new(codec));
+        let waiters = Arc::new(Mutex::new(Some(HashMap::new())));
+        let client = Client {
+            writer: tokio::sync::Mutex::new(writer),
+            waiters: waiters.clone(),
+            next_id: AtomicU64::new(0),
+            reader_task: tokio::spawn(read_responses(reader, waiters)),
+            keyspace: None,
+            version,
+            capabilities,
+            codec,
+        };
+        if let Some(credentials) = &options.credentials {
+            client.authenticate(credentials).await?;
+        }
+        Ok(client)
+    }
+
+    /// connect and log in, for servers that require authentication
+    pub async fn connect_with_credentials<A: ToSocketAddrs>(addr: A, credentials: Credentials) -> Result<Self> {
+        Client::connect_with(addr, ConnectOptions { credentials: Some(credentials), ..Default::default() }).await
     }
 
-    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        let resp = self.send_request(Request::Get { key }).await?;
+    /// log in, following requests run as `credentials.user`
+    pub async fn authenticate(&self, credentials: &Credentials) -> Result<()> {
+        let req = Request::Auth { user: credentials.user.clone(), password: credentials.password.clone() };
+        let resp = self.send_request(req).await?;
+        match resp {
+            Some(Response::Auth) => Ok(()),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// the protocol version spoken on this connection
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// the optional requests the server supports, others fail without a round trip
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// the serialization used on this connection
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// address `name` instead of the default keyspace in the following requests
+    pub fn use_keyspace(&mut self, name: impl Into<String>) {
+        self.keyspace = Some(name.into());
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let resp = self.send_request(Request::Get { keyspace: self.keyspace.clone(), key }).await?;
         match resp {
             Some(Response::Get(value)) => Ok(value),
             Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
@@ -45,8 +174,8 @@ impl Client {
         }
     }
 
-    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
-        let resp = self.send_request(Request::Set { key, value }).await?;
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        let resp = self.send_request(Request::Set { keyspace: self.keyspace.clone(), key, value }).await?;
         match resp {
             Some(Response::Set) => Ok(()),
             Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
@@ -55,8 +184,8 @@ impl Client {
         }
     }
 
-    pub async fn remove(&mut self, key: String) -> Result<()> {
-        let resp = self.send_request(Request::Remove { key }).await?;
+    pub async fn remove(&self, key: String) -> Result<()> {
+        let resp = self.send_request(Request::Remove { keyspace: self.keyspace.clone(), key }).await?;
         match resp {
             Some(Response::Remove) => Ok(()),
             Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
@@ -65,8 +194,248 @@ impl Client {
         }
     }
 
-    pub async fn send_request(&mut self, req: Request) -> Result<Option<Response>> {
-        self.writer.send(req).await?;
-        self.reader.try_next().await.map_err(|e| e.into())
+    pub async fn stats(&self) -> Result<EngineStats> {
+        let resp = self.send_request(Request::Stats { keyspace: self.keyspace.clone() }).await?;
+        match resp {
+            Some(Response::Stats(stats)) => Ok(stats),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    pub async fn create_keyspace(&self, name: String) -> Result<()> {
+        let resp = self.send_request(Request::CreateKeyspace { name }).await?;
+        match resp {
+            Some(Response::CreateKeyspace) => Ok(()),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    pub async fn drop_keyspace(&self, name: String) -> Result<()> {
+        let resp = self.send_request(Request::DropKeyspace { name }).await?;
+        match resp {
+            Some(Response::DropKeyspace) => Ok(()),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    pub async fn list_keyspaces(&self) -> Result<Vec<String>> {
+        let resp = self.send_request(Request::ListKeyspaces).await?;
+        match resp {
+            Some(Response::ListKeyspaces(names)) => Ok(names),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    pub async fn batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.require(Capabilities::BATCH, "batch")?;
+        let resp = self.send_request(Request::Batch { keyspace: self.keyspace.clone(), ops }).await?;
+        match resp {
+            Some(Response::Batch) => Ok(()),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// returns whether the value was swapped
+    pub async fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let resp = self.send_request(Request::Cas { keyspace: self.keyspace.clone(), key, expected, new }).await?;
+        match resp {
+            Some(Response::Cas(swapped)) => Ok(swapped),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    pub async fn scan(&self, start: String, end: Option<String>, limit: Option<usize>) -> Result<Vec<(String, String)>> {
+        self.require(Capabilities::SCAN, "scan")?;
+        let resp = self.send_request(Request::Scan { keyspace: self.keyspace.clone(), start, end, limit }).await?;
+        match resp {
+            Some(Response::Scan(pairs)) => Ok(pairs),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// check that the server answers
+    pub async fn ping(&self) -> Result<()> {
+        let resp = self.send_request(Request::Ping).await?;
+        match resp {
+            Some(Response::Pong) => Ok(()),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// whether the connection is known to be gone
+    pub fn is_closed(&self) -> bool {
+        self.waiters.lock().unwrap().is_none()
+    }
+
+    /// values of `keys` in one round trip, in the same order
+    pub async fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let resp = self.send_request(Request::MGet { keyspace: self.keyspace.clone(), keys }).await?;
+        match resp {
+            Some(Response::MGet(values)) => Ok(values),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// set all pairs in one round trip
+    pub async fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let resp = self.send_request(Request::MSet { keyspace: self.keyspace.clone(), pairs }).await?;
+        match resp {
+            Some(Response::MSet) => Ok(()),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// add `delta` to the counter at `key`, returns the new value
+    pub async fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let resp = self.send_request(Request::Incr { keyspace: self.keyspace.clone(), key, delta }).await?;
+        match resp {
+            Some(Response::Incr(value)) => Ok(value),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// append `suffix` to the value of `key`, returns the new length
+    pub async fn append(&self, key: String, suffix: String) -> Result<u64> {
+        let resp = self.send_request(Request::Append { keyspace: self.keyspace.clone(), key, suffix }).await?;
+        match resp {
+            Some(Response::Append(len)) => Ok(len),
+            Some(Response::Err(msg)) => Err(KvError::StringError(msg)),
+            Some(_) => Err(KvError::StringError("Invalid response".to_owned())),
+            None => Err(KvError::StringError("No response received".to_owned())),
+        }
+    }
+
+    /// Watch keys starting with `prefix`. The connection is dedicated to the
+    /// watch from now on, events are received until the stream is dropped.
+    pub async fn watch(self, prefix: String) -> Result<WatchStream> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let id = self.register(Waiter::Stream(tx))?;
+        self.send(id, Request::Watch { keyspace: self.keyspace.clone(), prefix }).await?;
+        match rx.recv().await {
+            Some(Response::Watch) => {},
+            Some(Response::Err(msg)) => return Err(KvError::StringError(msg)),
+            Some(_) => return Err(KvError::StringError("Invalid response".to_owned())),
+            None => return Err(KvError::StringError("No response received".to_owned())),
+        }
+
+        // the stream owns the client, dropping it closes the connection
+        Ok(Box::pin(stream::unfold((self, rx), |(client, mut rx)| async move {
+            let event = match rx.recv().await? {
+                Response::Event(event) => Ok(event),
+                Response::Err(msg) => Err(KvError::StringError(msg)),
+                _ => Err(KvError::StringError("Invalid response".to_owned())),
+            };
+            Some((event, (client, rx)))
+        })))
+    }
+
+    /// send `req` and wait for its response, `None` if the connection closed first
+    pub async fn send_request(&self, req: Request) -> Result<Option<Response>> {
+        let (tx, rx) = oneshot::channel();
+        let id = self.register(Waiter::Once(tx))?;
+        self.send(id, req).await?;
+        Ok(rx.await.ok())
+    }
+
+    fn require(&self, capability: Capabilities, name: &str) -> Result<()> {
+        if self.capabilities.contains(capability) {
+            Ok(())
+        } else {
+            Err(KvError::Unsupported(name.to_owned()))
+        }
+    }
+
+    fn register(&self, waiter: Waiter) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        match self.waiters.lock().unwrap().as_mut() {
+            Some(waiters) => {
+                waiters.insert(id, waiter);
+                Ok(id)
+            },
+            None => Err(KvError::StringError("Connection closed".to_owned())),
+        }
+    }
+
+    async fn send(&self, id: u64, req: Request) -> Result<()> {
+        let res = self.writer.lock().await.send(Tagged { id, msg: req }).await;
+        if res.is_err() {
+            if let Some(waiters) = self.waiters.lock().unwrap().as_mut() {
+                waiters.remove(&id);
+            }
+        }
+        Ok(res?)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+/// send our hello, returns the version, capabilities and codec the server agreed to
+async fn handshake(
+    frames_in: &mut FramedRead<ConnReader, LengthDelimitedCodec>,
+    frames_out: &mut FramedWrite<ConnWriter, LengthDelimitedCodec>,
+    codec: Option<Codec>,
+) -> Result<(u32, Capabilities, Codec)> {
+    let mut writer = SymmetricallyFramed::new(frames_out, SymmetricalBincode::<Hello>::default());
+    let mut reader = SymmetricallyFramed::new(frames_in, SymmetricalBincode::<HelloReply>::default());
+    writer.send(Hello::new(PROTOCOL_VERSION, Capabilities::all(), codec)).await?;
+    match reader.try_next().await? {
+        Some(HelloReply::Accepted { version, capabilities, codec }) if version >= MIN_PROTOCOL_VERSION => {
+            Ok((version, capabilities, codec))
+        },
+        Some(HelloReply::Accepted { version, .. }) => Err(KvError::Handshake(format!(
+            "server speaks protocol version {}, at least {} is needed", version, MIN_PROTOCOL_VERSION))),
+        Some(HelloReply::Rejected(msg)) => Err(KvError::Handshake(msg)),
+        None => Err(KvError::Handshake("connection closed".to_owned())),
+    }
+}
+
+/// hand every response to whoever waits for its id
+async fn read_responses(mut reader: SymmetricalReader<Tagged<Response>>, waiters: Arc<Mutex<Option<HashMap<u64, Waiter>>>>) {
+    while let Ok(Some(Tagged { id, msg })) = reader.try_next().await {
+        let mut waiters = waiters.lock().unwrap();
+        let waiters = match waiters.as_mut() {
+            Some(waiters) => waiters,
+            None => return,
+        };
+        match waiters.remove(&id) {
+            Some(Waiter::Once(tx)) => {
+                // the caller may have given up
+                let _ = tx.send(msg);
+            },
+            Some(Waiter::Stream(tx)) => {
+                if tx.send(msg).is_ok() {
+                    waiters.insert(id, Waiter::Stream(tx));
+                }
+            },
+            None => debug!("response to unknown request {}", id),
+        }
     }
+    // the connection is gone, dropping the waiters fails their calls
+    waiters.lock().unwrap().take();
 }
@@ -0,0 +1,75 @@
+use bytes::{Bytes, BytesMut};
+use serde::{Serialize, Deserialize};
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio_serde::{Deserializer, Serializer};
+use tokio_serde::formats::{SymmetricalBincode, SymmetricalJson};
+
+use crate::{KvError, Result};
+
+/// How `Tagged` requests and responses are serialized into frames. Picked
+/// by the client in the hello, the listener's default if it doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Bincode,
+    // readable in a packet capture
+    Json,
+    MessagePack,
+}
+
+impl FromStr for Codec {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bincode" => Ok(Codec::Bincode),
+            "json" => Ok(Codec::Json),
+            "msgpack" | "messagepack" => Ok(Codec::MessagePack),
+            _ => Err(KvError::StringError(format!("unknown codec: {}", s))),
+        }
+    }
+}
+
+/// A `tokio_serde` format for `T` that is chosen at runtime.
+pub struct WireFormat<T> {
+    codec: Codec,
+    bincode: SymmetricalBincode<T>,
+    json: SymmetricalJson<T>,
+}
+
+impl<T> WireFormat<T> {
+    pub fn new(codec: Codec) -> Self {
+        WireFormat { codec, bincode: Default::default(), json: Default::default() }
+    }
+}
+
+impl<T: Serialize + Unpin> Serializer<T> for WireFormat<T> {
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &T) -> io::Result<Bytes> {
+        let this = self.get_mut();
+        match this.codec {
+            Codec::Bincode => Pin::new(&mut this.bincode).serialize(item),
+            Codec::Json => Ok(Pin::new(&mut this.json).serialize(item)?),
+            Codec::MessagePack => rmp_serde::to_vec_named(item)
+                .map(Bytes::from)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl<T: for<'a> Deserialize<'a> + Unpin> Deserializer<T> for WireFormat<T> {
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> io::Result<T> {
+        let this = self.get_mut();
+        match this.codec {
+            Codec::Bincode => Pin::new(&mut this.bincode).deserialize(src),
+            Codec::Json => Ok(Pin::new(&mut this.json).deserialize(src)?),
+            Codec::MessagePack => rmp_serde::from_slice(src)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
@@ -1,10 +1,115 @@
 use serde::{Serialize, Deserialize};
+use std::ops::{BitAnd, BitOr};
+use crate::{BatchOp, Codec, EngineStats, WatchEvent};
+
+/// version of the `Request`/`Response` encoding, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+/// oldest version the server still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+// opens every hello, tells kvs clients from anything else
+const HELLO_MAGIC: u32 = u32::from_be_bytes(*b"KVS\0");
+
+/// Optional features of a peer, agreed on in the hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const BATCH: Capabilities = Capabilities(1);
+    pub const SCAN: Capabilities = Capabilities(1 << 1);
+    pub const TTL: Capabilities = Capabilities(1 << 2);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 3);
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// everything this version knows about
+    pub const fn all() -> Self {
+        Capabilities(0b1111)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+/// The first frame a client sends, before any `Tagged` request. Always
+/// bincode and its layout never changes, so any server can tell which
+/// version and codec a client speaks.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    magic: u32,
+    pub version: u32,
+    pub capabilities: Capabilities,
+    // for the frames after the handshake, the listener's default if `None`
+    pub codec: Option<Codec>,
+}
+
+impl Hello {
+    pub fn new(version: u32, capabilities: Capabilities, codec: Option<Codec>) -> Self {
+        Hello { magic: HELLO_MAGIC, version, capabilities, codec }
+    }
+
+    /// whether this came from a kvs client at all
+    pub fn is_valid(&self) -> bool {
+        self.magic == HELLO_MAGIC
+    }
+}
+
+/// the server's answer to a `Hello`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloReply {
+    // the version and capabilities both sides support, the codec of the following frames
+    Accepted { version: u32, capabilities: Capabilities, codec: Codec },
+    Rejected(String),
+}
+
+/// A request or response with the id the client picked for the request.
+/// Responses can arrive in any order, the id tells which request they answer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tagged<T> {
+    pub id: u64,
+    pub msg: T,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
+// `keyspace: None` addresses the default keyspace
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String } 
+    Get { keyspace: Option<String>, key: String },
+    Set { keyspace: Option<String>, key: String, value: String },
+    Remove { keyspace: Option<String>, key: String },
+    Stats { keyspace: Option<String> },
+    // long-lived, the server keeps pushing `Response::Event`
+    Watch { keyspace: Option<String>, prefix: String },
+    CreateKeyspace { name: String },
+    DropKeyspace { name: String },
+    ListKeyspaces,
+    Batch { keyspace: Option<String>, ops: Vec<BatchOp> },
+    Cas { keyspace: Option<String>, key: String, expected: Option<String>, new: Option<String> },
+    Scan { keyspace: Option<String>, start: String, end: Option<String>, limit: Option<usize> },
+    Incr { keyspace: Option<String>, key: String, delta: i64 },
+    Append { keyspace: Option<String>, key: String, suffix: String },
+    MGet { keyspace: Option<String>, keys: Vec<String> },
+    MSet { keyspace: Option<String>, pairs: Vec<(String, String)> },
+    Ping,
+    // `user` is `None` to log in with the shared secret
+    Auth { user: Option<String>, password: String },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,5 +117,25 @@ pub enum Response {
     Get(Option<String>),
     Set,
     Remove,
+    Stats(EngineStats),
+    // sent once the watch is registered, events follow
+    Watch,
+    Event(WatchEvent),
+    CreateKeyspace,
+    DropKeyspace,
+    ListKeyspaces(Vec<String>),
+    Batch,
+    // whether the value was swapped
+    Cas(bool),
+    Scan(Vec<(String, String)>),
+    // the new counter value
+    Incr(i64),
+    // the new length of the value
+    Append(u64),
+    // values in the order of the requested keys
+    MGet(Vec<Option<String>>),
+    MSet,
+    Pong,
+    Auth,
     Err(String),
 }
\ No newline at end of file
@@ -0,0 +1,149 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use rand::RngCore;
+
+use crate::{KvError, Result};
+
+// starts every encrypted log file, plaintext logs start with '{'
+pub(crate) const MAGIC: &[u8; 8] = b"KVSCRYPT";
+// magic, key id, nonce prefix
+pub(crate) const HEADER_LEN: usize = 8 + KEY_ID_LEN + NONCE_PREFIX_LEN;
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+const NONCE_PREFIX_LEN: usize = 16;
+
+/// Keys for encrypting `KvStore` log files with XChaCha20-Poly1305.
+///
+/// New files are always written with the current key. Old keys are only
+/// used to read files written before a rotation, compaction rewrites those
+/// with the current key.
+#[derive(Clone)]
+pub struct Cipher {
+    current: SealKey,
+    old: Vec<SealKey>,
+}
+
+#[derive(Clone)]
+struct SealKey {
+    // identifies the key in file headers without revealing it
+    id: [u8; KEY_ID_LEN],
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// a cipher from 32 raw key bytes
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != KEY_LEN {
+            return Err(KvError::Encryption(format!("key must be {} bytes, got {}", KEY_LEN, key.len())));
+        }
+        let aead = XChaCha20Poly1305::new_from_slice(key)
+            .map_err(|_| KvError::Encryption("invalid key".to_owned()))?;
+        // the tag of an empty message under a fixed nonce
+        let tag = aead.encrypt(&XNonce::default(), Payload { msg: b"", aad: b"kvs key id" })
+            .map_err(|_| KvError::Encryption("invalid key".to_owned()))?;
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&tag[..KEY_ID_LEN]);
+        Ok(Cipher { current: SealKey { id, aead }, old: Vec::new() })
+    }
+
+    /// a cipher from 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        Self::new(&decode_hex(hex.trim())?)
+    }
+
+    /// read the key from a file holding either 32 raw bytes or 64 hex digits
+    pub fn from_keyfile(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        if bytes.len() == KEY_LEN {
+            return Self::new(&bytes);
+        }
+        let hex = String::from_utf8(bytes)?;
+        Self::from_hex(&hex)
+    }
+
+    /// read the key as hex digits from the environment variable `var`
+    pub fn from_env(var: &str) -> Result<Self> {
+        let hex = env::var(var).map_err(|_| KvError::Encryption(format!("{} is not set", var)))?;
+        Self::from_hex(&hex)
+    }
+
+    /// also accept the keys of `old` when reading, for rotating to this key
+    pub fn with_old(mut self, old: Cipher) -> Self {
+        self.old.push(old.current);
+        self.old.extend(old.old);
+        self
+    }
+
+    /// header and sealer of a new file under the current key
+    pub(crate) fn new_file(&self) -> (Vec<u8>, FileSeal) {
+        let mut prefix = [0; NONCE_PREFIX_LEN];
+        rand::thread_rng().fill_bytes(&mut prefix);
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.current.id);
+        header.extend_from_slice(&prefix);
+        (header, FileSeal { aead: self.current.aead.clone(), prefix })
+    }
+
+    /// sealer of an existing file, and whether the file uses the current key
+    pub(crate) fn open_file(&self, header: &[u8]) -> Result<(FileSeal, bool)> {
+        if header.len() != HEADER_LEN || &header[..MAGIC.len()] != MAGIC {
+            return Err(KvError::Corrupted("bad log file header".to_owned()));
+        }
+        let id = &header[MAGIC.len()..MAGIC.len() + KEY_ID_LEN];
+        let mut prefix = [0; NONCE_PREFIX_LEN];
+        prefix.copy_from_slice(&header[MAGIC.len() + KEY_ID_LEN..]);
+
+        if id == self.current.id {
+            return Ok((FileSeal { aead: self.current.aead.clone(), prefix }, true));
+        }
+        match self.old.iter().find(|key| id == key.id) {
+            Some(key) => Ok((FileSeal { aead: key.aead.clone(), prefix }, false)),
+            None => Err(KvError::Encryption("log file was written with an unknown key".to_owned())),
+        }
+    }
+}
+
+/// Seals the records of one file. The nonce is the random prefix from the
+/// file header followed by the record offset, so it never repeats in a file
+/// and records can't be moved around undetected.
+#[derive(Clone)]
+pub(crate) struct FileSeal {
+    aead: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+impl FileSeal {
+    pub fn seal(&self, pos: u64, plain: &[u8]) -> Result<Vec<u8>> {
+        self.aead.encrypt(&self.nonce(pos), plain)
+            .map_err(|_| KvError::Encryption("failed to seal record".to_owned()))
+    }
+
+    pub fn open(&self, pos: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.aead.decrypt(&self.nonce(pos), sealed)
+            .map_err(|_| KvError::Corrupted(format!("record at {} failed authentication", pos)))
+    }
+
+    fn nonce(&self, pos: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.prefix);
+        nonce[NONCE_PREFIX_LEN..].copy_from_slice(&pos.to_be_bytes());
+        nonce
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(KvError::Encryption("key is not valid hex".to_owned()));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| KvError::Encryption("key is not valid hex".to_owned())))
+        .collect()
+}
@@ -0,0 +1,11 @@
+/// FNV-1a, stable across builds and platforms. KvStore routes keys to shards
+/// and the LSM engine builds its bloom filters with it, both end up on disk,
+/// so it must never change.
+pub(crate) fn hash_key(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key.as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
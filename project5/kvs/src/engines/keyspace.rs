@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::{KvError, Result};
+
+/// name of the keyspace used when a request doesn't name one
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+/// keyspace the memcached listener keeps its items in, the other protocols
+/// can't address it
+pub const MEMCACHE_KEYSPACE: &str = "memcached";
+
+const KEYSPACE_DIR: &str = "keyspaces";
+
+/// Keyspaces of a directory-based engine. The default keyspace lives in the
+/// root directory, named ones in `keyspaces/<name>`, each opened as an
+/// independent store by `open_fn`.
+pub(crate) struct KeyspaceRegistry<T> {
+    root: PathBuf,
+    spaces: RwLock<HashMap<String, Arc<T>>>,
+}
+
+impl<T> KeyspaceRegistry<T> {
+    pub fn open(root: PathBuf, open_fn: impl Fn(&Path) -> Result<T>) -> Result<Self> {
+        let mut spaces = HashMap::new();
+        spaces.insert(DEFAULT_KEYSPACE.to_owned(), Arc::new(open_fn(&root)?));
+
+        let dir = root.join(KEYSPACE_DIR);
+        if dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                    if path.is_dir() && valid_name(name) {
+                        spaces.insert(name.to_owned(), Arc::new(open_fn(&path)?));
+                    }
+                }
+            }
+        }
+
+        Ok(KeyspaceRegistry { root, spaces: RwLock::new(spaces) })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<T>> {
+        self.spaces.read().unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KvError::KeyspaceNotFound(name.to_owned()))
+    }
+
+    pub fn create(&self, name: &str, open_fn: impl Fn(&Path) -> Result<T>) -> Result<()> {
+        check_name(name)?;
+        let mut spaces = self.spaces.write().unwrap();
+        if spaces.contains_key(name) {
+            return Err(KvError::KeyspaceExists(name.to_owned()));
+        }
+        let path = self.root.join(KEYSPACE_DIR).join(name);
+        fs::create_dir_all(&path)?;
+        spaces.insert(name.to_owned(), Arc::new(open_fn(&path)?));
+        Ok(())
+    }
+
+    /// handles to the dropped keyspace fail once its files are gone
+    pub fn remove(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_KEYSPACE {
+            return Err(KvError::InvalidKeyspace(name.to_owned()));
+        }
+        let mut spaces = self.spaces.write().unwrap();
+        spaces.remove(name).ok_or_else(|| KvError::KeyspaceNotFound(name.to_owned()))?;
+        fs::remove_dir_all(self.root.join(KEYSPACE_DIR).join(name))?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.spaces.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// keyspace names double as directory and tree names
+pub(crate) fn check_name(name: &str) -> Result<()> {
+    if !valid_name(name) || name == DEFAULT_KEYSPACE {
+        return Err(KvError::InvalidKeyspace(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// a keyspace named by a client, the listeners' own keyspaces are off limits
+pub(crate) fn check_client_name(name: &str) -> Result<()> {
+    if name == MEMCACHE_KEYSPACE {
+        return Err(KvError::InvalidKeyspace(name.to_owned()));
+    }
+    Ok(())
+}
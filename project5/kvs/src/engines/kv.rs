@@ -3,26 +3,36 @@ use std::sync::RwLock;
 use std::{fmt};
 use std::fs::{self, OpenOptions};
 use std::fs::File;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use rand::seq::index;
 use serde::{Serialize, Deserialize};
 use serde_json::Deserializer;
 use std::io::{self, Seek, SeekFrom, Write, Read, BufReader, BufWriter};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, atomic::{AtomicU64}};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
 use dashmap::DashMap;
 use std::cell::{RefCell};
-use std::sync::atomic::Ordering;
 use log::{info, error};
 use tokio::sync::oneshot;
-use futures::Future;
+use futures::{future, Future};
 use std::pin::Pin;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use crossbeam_queue::ArrayQueue;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::error;
-use crate::{KvsEngine, KvError, Result, thread_pool::ThreadPool};
+use crate::{KvsEngine, EngineStats, KvError, Result, thread_pool::ThreadPool};
+use super::{add_delta, pool_stream, KvPairs, KvStream, KvValues, WatchStream};
+use super::watch::Notifier;
+use super::keyspace::{KeyspaceRegistry, DEFAULT_KEYSPACE};
+use super::crypto::{Cipher, FileSeal, HEADER_LEN, MAGIC};
+use super::limiter::IoLimiter;
+use super::hash::hash_key;
 
 const COMPACTION_LIMIT: u64 = 1024 * 1024;
+// holds the shard count of a sharded keyspace
+const SHARDS_FILE: &str = "SHARDS";
 
 // #[derive(Clone)]
 // pub struct KvStore(Arc<RwLock<ShardKvStore>>);
@@ -43,64 +53,221 @@ const COMPACTION_LIMIT: u64 = 1024 * 1024;
 
 #[derive(Clone)]
 pub struct KvStore<P: ThreadPool> {
+    // keyspace this handle reads and writes
+    keyspace: Arc<Keyspace>,
+    // every keyspace of the store, each with its own logs and index
+    keyspaces: Arc<KeyspaceRegistry<Keyspace>>,
+    // KvStore thread pool
+    pool: P,
+    // shard count of newly created keyspaces
+    shards: usize,
+    config: ShardConfig,
+}
+
+/// options for `KvStore::open_with`
+#[derive(Clone)]
+pub struct KvOptions {
+    /// Number of hash partitions, each with its own writer, log files and
+    /// compaction. Fixed once a keyspace is created.
+    pub shards: usize,
+    /// encrypt the log files, see `KvStore::open_encrypted`
+    pub cipher: Option<Cipher>,
+    /// Share of dead bytes above which a log file is compacted. Files
+    /// below it are left alone.
+    pub garbage_ratio: f64,
+    /// Bytes per second compaction may read and write, unlimited if `None`.
+    /// Slowed down further while reads and writes are slow.
+    pub compaction_rate: Option<u64>,
+}
+
+impl Default for KvOptions {
+    fn default() -> Self {
+        KvOptions { shards: 1, cipher: None, garbage_ratio: 0.5, compaction_rate: None }
+    }
+}
+
+/// settings shared by every shard of a store
+#[derive(Clone)]
+struct ShardConfig {
+    concurrency: usize,
+    garbage_ratio: f64,
+    // log files are encrypted if set
+    cipher: Option<Arc<Cipher>>,
+    // paces compaction of all shards, they share the disk
+    limiter: Arc<IoLimiter>,
+}
+
+impl<P: ThreadPool> KvStore<P> {
+    /// open a kv-store with a given directory
+    pub fn open(dir: impl Into<PathBuf>, concurrency: usize) -> Result<Self> {
+        Self::open_with(dir, concurrency, KvOptions::default())
+    }
+
+    /// Open a kv-store whose log files are encrypted with `cipher`.
+    /// Plaintext files and files under one of its old keys are rewritten
+    /// with the current key right away.
+    pub fn open_encrypted(dir: impl Into<PathBuf>, concurrency: usize, cipher: Cipher) -> Result<Self> {
+        Self::open_with(dir, concurrency, KvOptions { cipher: Some(cipher), ..KvOptions::default() })
+    }
+
+    pub fn open_with(dir: impl Into<PathBuf>, concurrency: usize, options: KvOptions) -> Result<Self> {
+        if options.shards == 0 {
+            return Err(KvError::StringError("shard count must be positive".to_owned()));
+        }
+        let shards = options.shards;
+        let config = ShardConfig {
+            concurrency,
+            garbage_ratio: options.garbage_ratio,
+            cipher: options.cipher.map(Arc::new),
+            limiter: Arc::new(IoLimiter::new(options.compaction_rate)),
+        };
+        let keyspaces = KeyspaceRegistry::open(dir.into(), |path| Keyspace::open(path, shards, &config))?;
+        Ok(
+            KvStore { 
+                keyspace: keyspaces.get(DEFAULT_KEYSPACE)?,
+                keyspaces: Arc::new(keyspaces),
+                pool: P::new(concurrency)?,
+                shards,
+                config,
+            }
+        )
+    }
+}
+
+/// A keyspace split into hash partitions. A single shard lives directly
+/// in the keyspace directory, more in `shard-<n>` subdirectories.
+struct Keyspace {
+    shards: Vec<Shard>,
+    notifier: Notifier,
+}
+
+impl Keyspace {
+    fn open(path: &Path, shards: usize, config: &ShardConfig) -> Result<Self> {
+        let shards = shard_count(path, shards)?;
+        let notifier = Notifier::new();
+        let shards = if shards == 1 {
+            vec![Shard::open(path, config, notifier.clone())?]
+        } else {
+            (0..shards)
+                .map(|i| {
+                    let dir = path.join(format!("shard-{}", i));
+                    fs::create_dir_all(&dir)?;
+                    Shard::open(&dir, config, notifier.clone())
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        Ok(Keyspace { shards, notifier })
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        (hash_key(key) % self.shards.len() as u64) as usize
+    }
+}
+
+/// The shard count of an existing keyspace has to match, keys would be
+/// looked up in the wrong shard otherwise.
+fn shard_count(path: &Path, requested: usize) -> Result<usize> {
+    let file = path.join(SHARDS_FILE);
+    let existing = if file.exists() {
+        Some(fs::read_to_string(&file)?.trim().parse::<usize>()
+            .map_err(|_| KvError::Corrupted(format!("bad shard count in {}", file.display())))?)
+    } else if !sorted_gen_list(path)?.is_empty() {
+        Some(1)
+    } else {
+        None
+    };
+
+    match existing {
+        Some(count) if count != requested => Err(KvError::StringError(
+            format!("{} was created with {} shards, not {}", path.display(), count, requested))),
+        Some(count) => Ok(count),
+        None => {
+            if requested > 1 {
+                fs::write(file, requested.to_string())?;
+            }
+            Ok(requested)
+        },
+    }
+}
+
+/// An independent log-structured store inside a directory
+struct Shard {
     // concurrent map string key -> command pos
     index_map: Arc<DashMap<String, CommandPos>>,
     // kv writer
     kv_writer: Arc<Mutex<KvWriter>>,
-    // kv reader
-    // kv_reader: KvReader,
-    // KvStore thread pool
-    pool: P,
     // reader queue
     reader_queue: Arc<ArrayQueue<KvReader>>,
+    // cloned into a fresh reader for long-running scans
+    reader: KvReader,
 }
 
-impl<P: ThreadPool> KvStore<P> {
-    /// open a kv-store with a given directory
-    pub fn open(dir: impl Into<PathBuf>, concurrency: usize) -> Result<Self> {
-        let dir_buf = Arc::new(dir.into());
-        let path = dir_buf.as_path();
+impl Shard {
+    fn open(path: &Path, config: &ShardConfig, notifier: Notifier) -> Result<Self> {
+        let concurrency = config.concurrency;
+        let cipher = config.cipher.clone();
+        let dir_buf = Arc::new(path.to_path_buf());
         let gen_list: Vec<u64> = sorted_gen_list(path)?;
 
-        let mut reader_map: HashMap<u64, BufReaderWithPos<File>> = HashMap::new();
+        let mut reader_map: HashMap<u64, LogReader> = HashMap::new();
         let mut index_map = Arc::new(DashMap::new());
+        let mut gens = BTreeMap::new();
         
         let mut curr_gen = 0;
-        let mut uncompacted = 0;
+        // some file isn't under the current key
+        let mut rewrite = false;
         for &gen in gen_list.iter() {
-            let f = File::open(log_path(path, gen))?;
-            let mut reader = BufReaderWithPos::new(f);
-            uncompacted += load(gen, &mut index_map, &mut reader)?;
+            let mut reader = LogReader::open(&log_path(path, gen), cipher.as_deref())?;
+            gens.insert(gen, GenStats::default());
+            load(gen, &mut index_map, &mut gens, &mut reader)?;
+            rewrite |= cipher.is_some() && !reader.current_key;
             reader_map.insert(gen, reader);
             curr_gen = gen;
         }
-        let first_gen = *gen_list.first().unwrap_or(&0);
 
         // every open create a new log file
         // TODO(wsl): BufReader & BufWriter -> the same file (cursor not share)
         curr_gen += 1;
-        let writer = new_log_file(path, curr_gen)?;
-        let f = File::open(log_path(path, curr_gen))?;
-        let reader = BufReaderWithPos::new(f);
+        let writer = new_log_file(path, curr_gen, cipher.as_deref())?;
+        let reader = LogReader::open(&log_path(path, curr_gen), cipher.as_deref())?;
         reader_map.insert(curr_gen, reader);
+        gens.insert(curr_gen, GenStats::default());
 
-        let safe_point = Arc::new(AtomicU64::new(first_gen));
+        let live_gens = Arc::new(RwLock::new(gens.keys().copied().collect()));
 
-        let kv_writer = Arc::new(Mutex::new(KvWriter {
+        let kv_writer = Arc::new_cyclic(|this| Mutex::new(KvWriter {
             path: dir_buf.clone(),
             curr_gen,
-            safe_point: safe_point.clone(),
+            live_gens: live_gens.clone(),
             writer,
             index_map: index_map.clone(),
             reader_map,
-            uncompacted,
+            gens,
+            garbage_ratio: config.garbage_ratio,
+            limiter: config.limiter.clone(),
+            compactions: 0,
+            last_compaction: None,
+            compacting: false,
+            this: this.clone(),
+            notifier,
+            cipher: cipher.clone(),
         }));
 
+        if rewrite {
+            info!("rewriting {} with the current encryption key", path.display());
+            kv_writer.lock().unwrap().rewrite_all()?;
+        }
+
         let reader = KvReader { 
             path: dir_buf,
-            safe_point: safe_point, 
+            live_gens, 
             index_map: index_map.clone(),
-            reader_map: Mutex::new(HashMap::new())
+            reader_map: Mutex::new(HashMap::new()),
+            cipher,
         };
 
         let reader_queue = Arc::new(ArrayQueue::new(concurrency));
@@ -108,21 +275,18 @@ impl<P: ThreadPool> KvStore<P> {
             reader_queue.push(reader.clone());
         }
 
-        Ok(
-            KvStore { 
-                index_map: index_map,
-                kv_writer,
-                pool: P::new(concurrency)?,
-                reader_queue,
-            }
-        )
+        Ok(Shard {
+            index_map: index_map,
+            kv_writer,
+            reader_queue,
+            reader,
+        })
     }
-    
 }
 
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
     fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        let writer = self.kv_writer.clone();
+        let writer = self.keyspace.shard(&key).kv_writer.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = writer.lock().unwrap().set(key, value);
@@ -139,7 +303,7 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     }
 
     fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        let writer = self.kv_writer.clone();
+        let writer = self.keyspace.shard(&key).kv_writer.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = writer.lock().unwrap().remove(key);
@@ -155,14 +319,209 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         )
     }
 
+    fn incr(&self, key: String, delta: i64) -> Pin<Box<dyn Future<Output = Result<i64>> + Send>> {
+        let writer = self.keyspace.shard(&key).kv_writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().incr(key, delta);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn append(&self, key: String, suffix: String) -> Pin<Box<dyn Future<Output = Result<u64>> + Send>> {
+        let writer = self.keyspace.shard(&key).kv_writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().append(key, suffix);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>)
+        -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        let writer = self.keyspace.shard(&key).kv_writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().compare_and_swap(key, expected, new);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    /// counters are summed over the shards, `current_gen` is the highest one
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<EngineStats>> + Send>> {
+        let writers: Vec<_> = self.keyspace.shards.iter().map(|shard| shard.kv_writer.clone()).collect();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writers.iter()
+                .map(|writer| writer.lock().unwrap().stats())
+                .collect::<Result<Vec<_>>>()
+                .map(merge_stats);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    /// iterate over a snapshot of the index positions, in no particular order
+    fn iter(&self) -> KvStream {
+        let iters: Vec<KvIter> = self.keyspace.shards.iter()
+            .map(|shard| {
+                let positions: Vec<(String, CommandPos)> = shard.index_map.iter()
+                    .map(|e| (e.key().clone(), e.value().clone()))
+                    .collect();
+                // a dedicated reader, so the shared queue isn't drained by a long scan
+                KvIter { reader: shard.reader.clone(), positions: positions.into_iter() }
+            })
+            .collect();
+        pool_stream(self.pool.clone(), iters.into_iter().flatten())
+    }
+
+    fn watch(&self, prefix: String) -> WatchStream {
+        self.keyspace.notifier.subscribe(prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        Ok(KvStore {
+            keyspace: self.keyspaces.get(name)?,
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keyspaces = self.keyspaces.clone();
+        let shards = self.shards;
+        let config = self.config.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = keyspaces.create(&name, |path| Keyspace::open(path, shards, &config));
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn drop_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keyspaces = self.keyspaces.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = keyspaces.remove(&name);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>> {
+        Box::pin(future::ready(Ok(self.keyspaces.list())))
+    }
+
+    /// all keys are read in one thread pool job
+    fn get_many(&self, keys: Vec<String>) -> Pin<Box<dyn Future<Output = Result<KvValues>> + Send>> {
+        let keyspace = self.keyspace.clone();
+        let limiter = self.config.limiter.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = keys.into_iter()
+                .map(|key| {
+                    let reader_queue = &keyspace.shard(&key).reader_queue;
+                    let reader = reader_queue.pop().unwrap();
+                    let start = Instant::now();
+                    let res = reader.get(key);
+                    limiter.record(start.elapsed());
+                    reader_queue.push(reader).unwrap();
+                    res
+                })
+                .collect();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    /// one thread pool job taking each shard's writer lock once
+    fn set_many(&self, pairs: KvPairs) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let keyspace = self.keyspace.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let mut by_shard: BTreeMap<usize, Vec<(String, String)>> = BTreeMap::new();
+            for (key, value) in pairs {
+                by_shard.entry(keyspace.shard_index(&key)).or_default().push((key, value));
+            }
+            let res = by_shard.into_iter().try_for_each(|(index, pairs)| {
+                let mut writer = keyspace.shards[index].kv_writer.lock().unwrap();
+                pairs.into_iter().try_for_each(|(key, value)| writer.set(key, value))
+            });
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
     fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
-        let reader_queue = self.reader_queue.clone();
+        let reader_queue = self.keyspace.shard(&key).reader_queue.clone();
+        let limiter = self.config.limiter.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             // len(reader_queue) == concurrency -> queue not empty when pop
             // if queue empty -> thread pool spawn blocking
             let reader = reader_queue.pop().unwrap();
+            let start = Instant::now();
             let res = reader.get(key);
+            limiter.record(start.elapsed());
             reader_queue.push(reader).unwrap();
             if tx.send(res).is_err() {
                 error!("Receiving end close");
@@ -181,50 +540,118 @@ pub struct KvWriter {
     path: Arc<PathBuf>,
     // current log number
     curr_gen: u64,
-    // safe point to sync the compaction gen number
-    safe_point: Arc<AtomicU64>,
+    // log files on disk, shared with the readers so they drop compacted ones
+    live_gens: Arc<RwLock<BTreeSet<u64>>>,
     // current log file writer
-    writer: BufWriterWithPos<File>,
+    writer: LogWriter,
     // concurrent map string key -> command pos
     index_map: Arc<DashMap<String, CommandPos>>,
-    // map u64 -> buf reader
-    reader_map: HashMap<u64, BufReaderWithPos<File>>,
-    // redundant bytes number
-    uncompacted: u64,
+    // map u64 -> log reader
+    reader_map: HashMap<u64, LogReader>,
+    // live/dead bytes of every log file
+    gens: BTreeMap<u64, GenStats>,
+    // files with a larger share of dead bytes get compacted
+    garbage_ratio: f64,
+    // paces compaction, fed with the latency of writes
+    limiter: Arc<IoLimiter>,
+    // compactions since open
+    compactions: u64,
+    last_compaction: Option<SystemTime>,
+    // a copy is running, no other compaction starts until it's swapped in
+    compacting: bool,
+    // handed to the thread of a throttled compaction
+    this: Weak<Mutex<KvWriter>>,
+    // told about every successful append
+    notifier: Notifier,
+    // new files are written with its current key
+    cipher: Option<Arc<Cipher>>,
 }
 
 impl KvWriter {
     /// Set the value of a string key to string
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = serde_json::to_vec(&Command::Set { key: key.clone(), value })?;
-        let pos = self.writer.pos;
+        let start = Instant::now();
+        let cmd = serde_json::to_vec(&Command::Set { key: key.clone(), value: value.clone() })?;
         // TODO(wsl): How to guarantee the atomicity of writing?
-        let len = self.writer.write(&cmd)?;
+        let (pos, len) = self.writer.append(&cmd)?;
         self.writer.flush()?;
+        self.limiter.record(start.elapsed());
+        self.notifier.notify(&key, Some(&value));
 
         // old command log redundant
-        if let Some(old_cmd) = self.index_map.insert(key, CommandPos { gen: self.curr_gen, pos, len: len as u64 }) {
-            self.uncompacted += old_cmd.len;
+        self.gen_stats(self.curr_gen).total += len;
+        if let Some(old_cmd) = self.index_map.insert(key, CommandPos { gen: self.curr_gen, pos, len }) {
+            self.gen_stats(old_cmd.gen).dead += old_cmd.len;
         }
 
-        if self.uncompacted > COMPACTION_LIMIT {
+        if !self.compacting && self.reclaimable() > COMPACTION_LIMIT {
             self.compaction()?;
         }
         Ok(())
     }
 
+    /// add `delta` to the counter at `key`, no other write can come in between
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = add_delta(self.current(&key)?.as_deref(), delta)?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// append `suffix` to the value of `key`, returns the new length
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        let mut value = self.current(&key)?.unwrap_or_default();
+        value.push_str(&suffix);
+        let len = value.len() as u64;
+        self.set(key, value)?;
+        Ok(len)
+    }
+
+    /// replace the value of `key` with `new` if it is `expected`, `None` meaning absent
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        if self.current(&key)? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if expected.is_some() => self.remove(key)?,
+            None => {},
+        }
+        Ok(true)
+    }
+
+    /// the value of `key`, read through the writer's own file handles
+    fn current(&mut self, key: &str) -> Result<Option<String>> {
+        let cmd_pos = match self.index_map.get(key) {
+            Some(cmd_pos) => cmd_pos.value().clone(),
+            None => return Ok(None),
+        };
+        let reader = self.reader_map.get_mut(&cmd_pos.gen).ok_or(KvError::ReaderNotFound)?;
+        match serde_json::from_slice(&reader.read_cmd(cmd_pos.pos, cmd_pos.len)?)? {
+            Command::Set { value, .. } => Ok(Some(value)),
+            Command::Remove { .. } => Ok(None),
+        }
+    }
+
     /// remove the value of the string key
     pub fn remove(&mut self, key: String) -> Result<()> {
         // only existent key need to remove
         if self.index_map.contains_key(&key) {
+            let start = Instant::now();
             let cmd = serde_json::to_vec(&Command::Remove { key: key.clone() })?;
-            let len = self.writer.write(&cmd)?;
+            let (_, len) = self.writer.append(&cmd)?;
             self.writer.flush()?;
+            self.limiter.record(start.elapsed());
+            self.notifier.notify(&key, None);
 
-            self.uncompacted += self.index_map.get(&key).map(|e| e.value().len).unwrap_or(0) + (len as u64);
-            self.index_map.remove(&key);
+            // the removal itself is garbage as soon as older files are gone
+            let stats = self.gen_stats(self.curr_gen);
+            stats.total += len;
+            stats.tombstones += len;
+            if let Some((_, old_cmd)) = self.index_map.remove(&key) {
+                self.gen_stats(old_cmd.gen).dead += old_cmd.len;
+            }
 
-            if self.uncompacted > COMPACTION_LIMIT {
+            if !self.compacting && self.reclaimable() > COMPACTION_LIMIT {
                 self.compaction()?;
             }
             Ok(())
@@ -233,109 +660,393 @@ impl KvWriter {
         }
     }
 
-    /// compact out-of-date log
-    fn compaction(&mut self) -> Result<()> {
-        let dir = self.path.as_path();
-        let temp_gen = self.curr_gen + 1;
-        let mut writer = new_log_file(dir, temp_gen)?;
+    /// statistics of the log files and the index
+    pub fn stats(&self) -> Result<EngineStats> {
+        let gen_list = sorted_gen_list(&self.path)?;
+        let mut total_bytes = 0;
+        for &gen in gen_list.iter() {
+            total_bytes += fs::metadata(log_path(&self.path, gen))?.len();
+        }
 
-        // index_map -> currently valid (key, value)
-        for mut cmd_pos in self.index_map.iter_mut() {
-            if let Some(reader) = self.reader_map.get_mut(&cmd_pos.gen) {
-                // move reader to log pointer and read command
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-                let mut buf = vec![0; cmd_pos.len as usize];
-                reader.read_exact(&mut buf)?;
+        Ok(EngineStats {
+            engine: "kvs".to_owned(),
+            keys: Some(self.index_map.len() as u64),
+            uncompacted: Some(self.gens.iter().map(|(&gen, stats)| stats.garbage(self.is_oldest(gen))).sum()),
+            total_bytes: Some(total_bytes),
+            generations: Some(gen_list.len() as u64),
+            current_gen: Some(self.curr_gen),
+            last_compaction: self.last_compaction.map(unix_secs),
+            compactions: Some(self.compactions),
+        })
+    }
 
-                let pos = writer.pos;
-                writer.write_all(&buf)?;
+    fn gen_stats(&mut self, gen: u64) -> &mut GenStats {
+        self.gens.entry(gen).or_default()
+    }
 
-                cmd_pos.gen = temp_gen;
-                cmd_pos.pos = pos;
-            } else {
-                // println!("tempgen: {} gen: {}", temp_gen, cmd_pos.gen);
-                // println!("index map: {:?} reader_map: {:?}", self.index_map, self.reader_map.keys());
-                return Err(KvError::ReaderNotFound);
-            }
+    /// Removals only go away with the last older file, before that they're
+    /// copied by every compaction and count as live.
+    fn is_oldest(&self, gen: u64) -> bool {
+        self.gens.keys().next() == Some(&gen)
+    }
+
+    /// log files above the garbage ratio
+    fn compactable(&self) -> Vec<u64> {
+        self.gens.iter()
+            .filter(|&(&gen, stats)| stats.ratio(self.is_oldest(gen)) > self.garbage_ratio)
+            .map(|(&gen, _)| gen)
+            .collect()
+    }
+
+    /// garbage bytes in files above the garbage ratio
+    fn reclaimable(&self) -> u64 {
+        self.compactable().into_iter().map(|gen| self.gens[&gen].garbage(self.is_oldest(gen))).sum()
+    }
+
+    /// compact the log files above the garbage ratio
+    ///
+    /// A throttled copy would hold up the writes of the shard and a pool
+    /// thread, so it runs on a thread of its own.
+    fn compaction(&mut self) -> Result<()> {
+        let job = self.begin_compaction(self.compactable())?;
+        if !self.limiter.is_limited() {
+            return self.compact_now(job);
         }
-        // flush written log after compaction finish
-        writer.flush()?;
+        let this = self.this.clone();
+        let spawned = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || compact_in_background(this, job));
+        if let Err(e) = spawned {
+            self.compacting = false;
+            return Err(e.into());
+        }
+        Ok(())
+    }
 
-        // create compaction log file reader
-        let reader = BufReaderWithPos::new(File::open(log_path(dir, temp_gen))?);
-        self.reader_map.insert(temp_gen,  reader);
+    /// compact every log file, rewriting all of them under the current key
+    fn rewrite_all(&mut self) -> Result<()> {
+        let gens: Vec<u64> = self.gens.keys().copied().collect();
+        let job = self.begin_compaction(gens)?;
+        self.compact_now(job)
+    }
 
-        // store the current gen number
-        self.safe_point.store(temp_gen, Ordering::SeqCst);
+    /// copy while holding the writer
+    fn compact_now(&mut self, mut job: Compaction) -> Result<()> {
+        match job.copy(|| false) {
+            Ok(()) => self.finish_compaction(job),
+            Err(e) => {
+                self.abandon_compaction(job);
+                Err(e)
+            },
+        }
+    }
+
+    /// Close the active file and set up the copy of `gens` into a new one.
+    ///
+    /// The active file is closed first, so it can be compacted too. The new
+    /// file is numbered above every existing one, records copied there stay
+    /// the newest of their key when the logs are replayed.
+    fn begin_compaction(&mut self, gens: Vec<u64>) -> Result<Compaction> {
+        let dir = self.path.clone();
+        let temp_gen = self.curr_gen + 1;
+        let cipher = self.cipher.clone();
+        let writer = new_log_file(&dir, temp_gen, cipher.as_deref())?;
 
         // create new log file writer & reader
         self.curr_gen += 2;
-        self.writer = new_log_file(dir, self.curr_gen)?;
-        let reader = BufReaderWithPos::new(File::open(log_path(dir, self.curr_gen))?);
+        self.writer = new_log_file(&dir, self.curr_gen, cipher.as_deref())?;
+        let reader = LogReader::open(&log_path(&dir, self.curr_gen), cipher.as_deref())?;
         self.reader_map.insert(self.curr_gen,  reader);
+        self.gens.insert(self.curr_gen, GenStats::default());
+        self.live_gens.write().unwrap().insert(self.curr_gen);
+
+        // the copy reads through its own handles, the writer keeps serving reads
+        let compacted: BTreeSet<u64> = gens.into_iter().collect();
+        let mut sources = Vec::with_capacity(compacted.len());
+        for &gen in compacted.iter() {
+            // a removal still hides the key from older files that stay
+            let keeps_older = self.gens.keys().any(|&g| g < gen && !compacted.contains(&g));
+            sources.push((gen, keeps_older, LogReader::open(&log_path(&dir, gen), cipher.as_deref())?));
+        }
+
+        let total = compacted.iter().filter_map(|gen| self.gens.get(gen)).map(|stats| stats.total).sum();
+        info!("compacting {} files ({} bytes) in {}", compacted.len(), total, dir.display());
+        self.compacting = true;
+        Ok(Compaction {
+            progress: Progress::new(dir.clone(), total),
+            dir,
+            temp_gen,
+            sources,
+            writer,
+            index_map: self.index_map.clone(),
+            limiter: self.limiter.clone(),
+            moved: Vec::new(),
+            stats: GenStats::default(),
+        })
+    }
+
+    /// Swap the flushed copy in for the compacted files.
+    fn finish_compaction(&mut self, job: Compaction) -> Result<()> {
+        self.compacting = false;
+        let Compaction { dir, temp_gen, sources, moved, mut stats, progress, .. } = job;
+
+        // create compaction log file reader
+        let reader = LogReader::open(&log_path(&dir, temp_gen), self.cipher.as_deref())?;
+        self.reader_map.insert(temp_gen,  reader);
+        self.live_gens.write().unwrap().insert(temp_gen);
+
+        // keys written again since they were copied keep their newer position
+        for (key, old, new) in moved {
+            match self.index_map.get_mut(&key) {
+                Some(mut cmd_pos) if cmd_pos.gen == old.gen && cmd_pos.pos == old.pos => *cmd_pos = new,
+                _ => stats.dead += new.len,
+            }
+        }
+        self.gens.insert(temp_gen, stats);
 
         // remove stale files
         // The file cannot be removed immediately because the `KvReader` still keep the file handle.
         // When `KvReader` used next, it will clear the file handle 
-        let stale_gens = sorted_gen_list(&self.path)?.into_iter().filter(|gen| *gen < temp_gen);
-        for gen in stale_gens {
+        {
+            let mut live_gens = self.live_gens.write().unwrap();
+            for (gen, _, _) in sources.iter() {
+                live_gens.remove(gen);
+            }
+        }
+        for (gen, _, _) in sources {
             self.reader_map.remove(&gen);
-            fs::remove_file(log_path(dir, gen))?;
+            self.gens.remove(&gen);
+            fs::remove_file(log_path(&dir, gen))?;
         }
 
-        self.uncompacted = 0;
+        self.compactions += 1;
+        self.last_compaction = Some(SystemTime::now());
+        info!("compacted {} in {:?}, kept {} of {} bytes",
+            dir.display(), progress.start.elapsed(), self.gens[&temp_gen].total, progress.total);
         Ok(())
     }
+
+    fn abandon_compaction(&mut self, job: Compaction) {
+        self.compacting = false;
+        job.abandon();
+    }
+}
+
+/// Copy without the writer lock, it's only taken to swap the files in.
+/// Closing the store cancels the copy.
+fn compact_in_background(writer: Weak<Mutex<KvWriter>>, mut job: Compaction) {
+    let dir = job.dir.clone();
+    let copied = job.copy(|| writer.strong_count() == 0);
+    let shard = match writer.upgrade() {
+        Some(shard) => shard,
+        None => return job.abandon(),
+    };
+    let mut writer = shard.lock().unwrap();
+    let res = match copied {
+        Ok(()) => writer.finish_compaction(job),
+        Err(e) => {
+            writer.abandon_compaction(job);
+            Err(e)
+        },
+    };
+    if let Err(e) = res {
+        error!("compaction of {} failed: {}", dir.display(), e);
+    }
+}
+
+/// A compaction between the steps taken under the writer lock.
+///
+/// New positions are only published once the copy is flushed, a reader
+/// following the index never runs past the end of the new file.
+struct Compaction {
+    dir: Arc<PathBuf>,
+    temp_gen: u64,
+    // compacted file, whether its removals still hide older files, its reader
+    sources: Vec<(u64, bool, LogReader)>,
+    writer: LogWriter,
+    index_map: Arc<DashMap<String, CommandPos>>,
+    limiter: Arc<IoLimiter>,
+    // copied sets as key, old position, new position
+    moved: Vec<(String, CommandPos, CommandPos)>,
+    stats: GenStats,
+    progress: Progress,
+}
+
+impl Compaction {
+    /// copy the live records and flush them, `cancelled` is checked before each record
+    fn copy(&mut self, cancelled: impl Fn() -> bool) -> Result<()> {
+        let Compaction { temp_gen, sources, writer, index_map, limiter, moved, stats, progress, .. } = self;
+        for (gen, keeps_older, reader) in sources.iter_mut() {
+            let (gen, keeps_older) = (*gen, *keeps_older);
+            for_each_record(reader, |cmd, pos, len| {
+                if cancelled() {
+                    return Err(KvError::StringError("compaction cancelled".to_owned()));
+                }
+                limiter.acquire(len);
+                progress.read(len);
+                let live = match &cmd {
+                    Command::Set { key, .. } => index_map.get(key)
+                        .is_some_and(|cmd_pos| cmd_pos.gen == gen && cmd_pos.pos == pos),
+                    Command::Remove { key } => keeps_older && !index_map.contains_key(key),
+                };
+                if !live {
+                    return Ok(());
+                }
+
+                // encrypted commands are sealed again for their new position
+                let (new_pos, new_len) = writer.append(&serde_json::to_vec(&cmd)?)?;
+                limiter.acquire(new_len);
+                stats.total += new_len;
+                match cmd {
+                    Command::Set { key, .. } => moved.push((key,
+                        CommandPos { gen, pos, len },
+                        CommandPos { gen: *temp_gen, pos: new_pos, len: new_len })),
+                    Command::Remove { .. } => stats.tombstones += new_len,
+                }
+                Ok(())
+            })?;
+        }
+        writer.flush()
+    }
+
+    /// delete the partly written copy
+    fn abandon(self) {
+        let path = log_path(&self.dir, self.temp_gen);
+        drop(self.writer);
+        if let Err(e) = fs::remove_file(&path) {
+            error!("can't remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// logs how far a compaction got, every 10 percent of the input
+struct Progress {
+    dir: Arc<PathBuf>,
+    start: Instant,
+    // bytes of the compacted files
+    total: u64,
+    read: u64,
+    // last logged percentage
+    logged: u64,
+}
+
+impl Progress {
+    fn new(dir: Arc<PathBuf>, total: u64) -> Self {
+        Progress { dir, start: Instant::now(), total, read: 0, logged: 0 }
+    }
+
+    fn read(&mut self, len: u64) {
+        self.read += len;
+        let percent = (self.read * 100).checked_div(self.total).unwrap_or(100).min(100);
+        if percent >= self.logged + 10 {
+            self.logged = percent - percent % 10;
+            info!("compacting {}: {}% ({}/{} bytes) after {:?}",
+                self.dir.display(), self.logged, self.read, self.total, self.start.elapsed());
+        }
+    }
+}
+
+/// Bytes written to a log file and how many of them are superseded
+#[derive(Debug, Default)]
+struct GenStats {
+    total: u64,
+    dead: u64,
+    // removals, garbage once no older file is left
+    tombstones: u64,
+}
+
+impl GenStats {
+    /// bytes a compaction of this file would drop
+    fn garbage(&self, oldest: bool) -> u64 {
+        if oldest { self.dead + self.tombstones } else { self.dead }
+    }
+
+    fn ratio(&self, oldest: bool) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.garbage(oldest) as f64 / self.total as f64
+    }
 }
 
 pub struct KvReader {
     path: Arc<PathBuf>,
-    // safe point to sync the compaction gen number
-    safe_point: Arc<AtomicU64>,
+    // log files not yet compacted away
+    live_gens: Arc<RwLock<BTreeSet<u64>>>,
     // concurrent map string key -> command pos
     index_map: Arc<DashMap<String, CommandPos>>,
-    // map gen number -> bufReader
-    reader_map: Mutex<HashMap<u64, BufReaderWithPos<File>>>,
+    // map gen number -> log reader
+    reader_map: Mutex<HashMap<u64, LogReader>>,
+    cipher: Option<Arc<Cipher>>,
 }
 
 impl KvReader {
     /// Get the value of the string key
     pub fn get(&self, key: String) -> Result<Option<String>> {
-        let safe_point = self.safe_point.load(Ordering::SeqCst);
-        
-        // println!("reader_map: {:?} safe point: {}", self.reader_map.borrow().keys(), safe_point);
         // remove the stale file handle
-        self.reader_map.lock().unwrap().retain(|&k, _| k >= safe_point);
+        {
+            let live_gens = self.live_gens.read().unwrap();
+            self.reader_map.lock().unwrap().retain(|k, _| live_gens.contains(k));
+        }
         
-        if let Some(cmd_pos) = self.index_map.get(&key) {
-            let cmd_pos = cmd_pos.value();
-            // if the reader hashmap not contains the key, open corresponding file ans create the buf reader
-            let mut readers = self.reader_map.lock().unwrap();
-            if !readers.contains_key(&cmd_pos.gen) { 
-                let file = File::open(log_path(self.path.as_path(), cmd_pos.gen))?;
-                let reader = BufReaderWithPos::new(file);
-                readers.insert(cmd_pos.gen, reader);
-            }
-            
-            let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-            // move reader to log pointer and read command
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let mut buf = vec![0; cmd_pos.len as usize];
-            reader.read_exact(&mut buf)?;
-            let cmd: Command = serde_json::from_slice(&buf)?;
-            // println!("cmd: {}", cmd);
-            match cmd {
-                Command::Set {key: _, value} => {
-                    Ok(Some(value))
-                },
-                Command::Remove { key: _ } => {
-                    Ok(None)
-                }
+        let cmd_pos = match self.index_map.get(&key) {
+            Some(cmd_pos) => cmd_pos.value().clone(),
+            None => return Ok(None),
+        };
+        self.read_value(&cmd_pos)
+    }
+
+    /// read the value of a command position taken from a snapshot of the index,
+    /// the key is looked up again if its log file was compacted meanwhile
+    pub fn get_at(&self, key: String, cmd_pos: &CommandPos) -> Result<Option<String>> {
+        if !self.live_gens.read().unwrap().contains(&cmd_pos.gen) {
+            return self.get(key);
+        }
+        self.read_value(cmd_pos)
+    }
+
+    fn read_value(&self, cmd_pos: &CommandPos) -> Result<Option<String>> {
+        // if the reader hashmap not contains the key, open corresponding file ans create the buf reader
+        let mut readers = self.reader_map.lock().unwrap();
+        if !readers.contains_key(&cmd_pos.gen) { 
+            let reader = LogReader::open(&log_path(self.path.as_path(), cmd_pos.gen), self.cipher.as_deref())?;
+            readers.insert(cmd_pos.gen, reader);
+        }
+        
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        let buf = reader.read_cmd(cmd_pos.pos, cmd_pos.len)?;
+        let cmd: Command = serde_json::from_slice(&buf)?;
+        // println!("cmd: {}", cmd);
+        match cmd {
+            Command::Set {key: _, value} => {
+                Ok(Some(value))
+            },
+            Command::Remove { key: _ } => {
+                Ok(None)
             }
-        } else {
-            Ok(None)
         }
-        // Ok(None)
+    }
+}
+
+/// Walks a snapshot of the index, reading values on demand.
+struct KvIter {
+    reader: KvReader,
+    positions: std::vec::IntoIter<(String, CommandPos)>,
+}
+
+impl Iterator for KvIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, cmd_pos) in self.positions.by_ref() {
+            // keys removed after the snapshot are skipped
+            match self.reader.get_at(key.clone(), &cmd_pos) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
     }
 }
 
@@ -344,9 +1055,10 @@ impl Clone for KvReader {
     fn clone(&self) -> Self {
         KvReader { 
             path: self.path.clone(),
-            safe_point: self.safe_point.clone(),
+            live_gens: self.live_gens.clone(),
             index_map: self.index_map.clone(),
             reader_map: Mutex::new(HashMap::new()),
+            cipher: self.cipher.clone(),
         }
     }
 }
@@ -357,13 +1069,116 @@ impl std::fmt::Debug for KvReader {
     }
 }
 
+fn merge_stats(shards: Vec<EngineStats>) -> EngineStats {
+    fn sum(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+        Some(a.unwrap_or(0) + b.unwrap_or(0))
+    }
+    shards.into_iter()
+        .reduce(|total, shard| EngineStats {
+            engine: total.engine,
+            keys: sum(total.keys, shard.keys),
+            uncompacted: sum(total.uncompacted, shard.uncompacted),
+            total_bytes: sum(total.total_bytes, shard.total_bytes),
+            generations: sum(total.generations, shard.generations),
+            current_gen: total.current_gen.max(shard.current_gen),
+            last_compaction: total.last_compaction.max(shard.last_compaction),
+            compactions: sum(total.compactions, shard.compactions),
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}", gen))
 }
 
-fn new_log_file(dir: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+/// create a log file, starting with the encryption header if there's a cipher
+fn new_log_file(dir: &Path, gen: u64, cipher: Option<&Cipher>) -> Result<LogWriter> {
     let f = OpenOptions::new().create(true).append(true).open(log_path(dir, gen))?;
-    Ok(BufWriterWithPos::new(f))
+    let mut writer = BufWriterWithPos::new(f);
+    let seal = match cipher {
+        Some(cipher) => {
+            let (header, seal) = cipher.new_file();
+            writer.write_all(&header)?;
+            // readers parse the header as soon as the file exists
+            writer.flush()?;
+            Some(seal)
+        },
+        None => None,
+    };
+    Ok(LogWriter { writer, seal })
+}
+
+/// Appends commands to a log file. Encrypted commands are stored as a
+/// little-endian u32 length followed by the sealed JSON.
+struct LogWriter {
+    writer: BufWriterWithPos<File>,
+    seal: Option<FileSeal>,
+}
+
+impl LogWriter {
+    /// append a serialized command, returns its position and length in the file
+    fn append(&mut self, cmd: &[u8]) -> Result<(u64, u64)> {
+        let pos = self.writer.pos;
+        match &self.seal {
+            Some(seal) => {
+                let sealed = seal.seal(pos, cmd)?;
+                self.writer.write_u32::<LittleEndian>(sealed.len() as u32)?;
+                self.writer.write_all(&sealed)?;
+                Ok((pos, 4 + sealed.len() as u64))
+            },
+            None => {
+                self.writer.write_all(cmd)?;
+                Ok((pos, cmd.len() as u64))
+            },
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads commands back from a log file, plaintext or encrypted.
+struct LogReader {
+    reader: BufReaderWithPos<File>,
+    seal: Option<FileSeal>,
+    // plaintext files count as not under the current key
+    current_key: bool,
+}
+
+impl LogReader {
+    fn open(path: &Path, cipher: Option<&Cipher>) -> Result<Self> {
+        let mut reader = BufReaderWithPos::new(File::open(path)?);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        reader.by_ref().take(HEADER_LEN as u64).read_to_end(&mut header)?;
+
+        if !header.starts_with(MAGIC) {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(LogReader { reader, seal: None, current_key: false });
+        }
+        let cipher = cipher.ok_or_else(|| KvError::Encryption(
+            format!("{} is encrypted but no key is configured", path.display())))?;
+        let (seal, current_key) = cipher.open_file(&header)?;
+        Ok(LogReader { reader, seal: Some(seal), current_key })
+    }
+
+    /// the serialized command at `pos`, opened if the file is encrypted
+    fn read_cmd(&mut self, pos: u64, len: u64) -> Result<Vec<u8>> {
+        // move reader to log pointer and read command
+        self.reader.seek(SeekFrom::Start(pos))?;
+        let mut buf = vec![0; len as usize];
+        self.reader.read_exact(&mut buf)?;
+        match &self.seal {
+            Some(seal) if buf.len() >= 4 => seal.open(pos, &buf[4..]),
+            Some(_) => Err(KvError::Corrupted(format!("record at {} is truncated", pos))),
+            None => Ok(buf),
+        }
+    }
 }
 
 /// sort the log file number in ascending order
@@ -379,37 +1194,61 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 }
 
 /// load log file and fill the index map
-fn load(gen: u64, index_map: &mut Arc<DashMap<String, CommandPos>>, reader: &mut BufReaderWithPos<File>) -> Result<u64> {
+fn load(gen: u64, index_map: &mut Arc<DashMap<String, CommandPos>>, gens: &mut BTreeMap<u64, GenStats>, log: &mut LogReader) -> Result<()> {
+    for_each_record(log, |cmd, pos, len| {
+        gens.entry(gen).or_default().total += len;
+        let cmd_pos = CommandPos { gen, pos, len };
+        let old_cmd = match cmd {
+            Command::Set{key, value: _} => index_map.insert(key, cmd_pos),
+            Command::Remove { key } => {
+                gens.entry(gen).or_default().tombstones += len;
+                index_map.remove(&key).map(|(_, old_cmd)| old_cmd)
+            }
+        };
+        if let Some(old_cmd) = old_cmd {
+            gens.entry(old_cmd.gen).or_default().dead += old_cmd.len;
+        }
+        Ok(())
+    })
+}
+
+/// call `f` with every command of a log file, its position and length
+fn for_each_record(log: &mut LogReader, mut f: impl FnMut(Command, u64, u64) -> Result<()>) -> Result<()> {
+    if let Some(seal) = &log.seal {
+        let reader = &mut log.reader;
+        let mut pos = HEADER_LEN as u64;
+        reader.seek(SeekFrom::Start(pos))?;
+        loop {
+            let len = match reader.read_u32::<LittleEndian>() {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            let mut sealed = vec![0; len as usize];
+            reader.read_exact(&mut sealed)?;
+            let cmd: Command = serde_json::from_slice(&seal.open(pos, &sealed)?)?;
+
+            let len = 4 + len as u64;
+            f(cmd, pos, len)?;
+            pos += len;
+        }
+        return Ok(());
+    }
+
+    log.reader.seek(SeekFrom::Start(0))?;
     // Creates a JSON deserializer from an io::Read
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut stream = Deserializer::from_reader(&mut log.reader).into_iter::<Command>();
     // Get current position 
     let mut offset = stream.byte_offset();
-    let mut uncompacted = 0;
 
     while let Some(cmd) = stream.next() {
         let c = cmd?;
         // println!("command: {}", c);
         let curr_offset = stream.byte_offset();
-        match c {
-            Command::Set{key, value: _} => {
-                if let Some(old_cmd) = index_map.insert(key, CommandPos {
-                    gen,
-                    pos: offset as u64,
-                    len: (curr_offset - offset) as u64,
-                }) {
-                    uncompacted += old_cmd.len;
-                }
-            },
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index_map.remove(&key) {
-                    uncompacted += old_cmd.1.len;
-                }
-                uncompacted += (curr_offset - offset) as u64;
-            }
-        }
+        f(c, offset as u64, (curr_offset - offset) as u64)?;
         offset = curr_offset;
     }
-    Ok(uncompacted)
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
@@ -430,7 +1269,7 @@ impl fmt::Display for Command {
 }
 
 /// gen -> file number, pos: file position, len: command length
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CommandPos {
     gen: u64,
     pos: u64,
@@ -0,0 +1,84 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// foreground latency above which compaction slows down
+const SLOW_REQUEST: Duration = Duration::from_millis(10);
+// compaction rate is divided by this while requests are slow
+const BACKOFF: u64 = 4;
+// floor of the backed off rate
+const MIN_RATE: u64 = 64 * 1024;
+
+/// Token bucket pacing the compaction IO of a store.
+///
+/// Foreground requests report their latency, and while its moving average
+/// is above `SLOW_REQUEST` the rate drops to a quarter. Without a rate
+/// compaction runs unthrottled and latency is ignored.
+pub(crate) struct IoLimiter {
+    // bytes per second
+    rate: Option<u64>,
+    bucket: Mutex<Bucket>,
+    // exponentially weighted request latency in microseconds
+    latency_us: AtomicU64,
+}
+
+struct Bucket {
+    // may go negative, a large record is paid off by sleeping
+    tokens: f64,
+    last: Instant,
+}
+
+impl IoLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        IoLimiter {
+            rate,
+            bucket: Mutex::new(Bucket { tokens: rate.unwrap_or(0) as f64, last: Instant::now() }),
+            latency_us: AtomicU64::new(0),
+        }
+    }
+
+    /// whether compaction IO is paced at all
+    pub fn is_limited(&self) -> bool {
+        self.rate.is_some()
+    }
+
+    /// record the latency of a foreground request
+    pub fn record(&self, latency: Duration) {
+        let sample = latency.as_micros() as u64;
+        let _ = self.latency_us.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+            Some(avg - avg / 8 + sample / 8)
+        });
+    }
+
+    /// whether foreground requests are currently slow
+    pub fn backing_off(&self) -> bool {
+        self.latency_us.load(Ordering::Relaxed) > SLOW_REQUEST.as_micros() as u64
+    }
+
+    /// block until `bytes` of compaction IO are allowed
+    pub fn acquire(&self, bytes: u64) {
+        let rate = match self.rate {
+            Some(rate) if self.backing_off() => (rate / BACKOFF).max(MIN_RATE.min(rate)),
+            Some(rate) => rate,
+            None => return,
+        } as f64;
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * rate;
+            // at most a second worth of burst
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.last = now;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
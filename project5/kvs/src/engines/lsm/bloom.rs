@@ -0,0 +1,65 @@
+use std::io::{self, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::engines::hash::hash_key;
+
+// ~1% false positive rate with 10 bits per key and 7 probes
+const BITS_PER_KEY: usize = 10;
+const NUM_PROBES: u32 = 7;
+
+/// Bloom filter attached to every sstable, so lookups can skip tables
+/// that certainly don't contain the key.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    k: u32,
+}
+
+impl BloomFilter {
+    /// build a filter from the hashes of all keys in a table
+    pub fn from_hashes(hashes: &[u64]) -> Self {
+        let nbits = (hashes.len() * BITS_PER_KEY).max(64);
+        let mut filter = BloomFilter {
+            bits: vec![0; nbits.div_ceil(8)],
+            k: NUM_PROBES,
+        };
+        for &h in hashes {
+            filter.insert(h);
+        }
+        filter
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let nbits = self.bits.len() as u64 * 8;
+        for idx in probes(hash, self.k, nbits) {
+            self.bits[(idx / 8) as usize] |= 1 << (idx % 8);
+        }
+    }
+
+    /// `false` means the key is definitely absent
+    pub fn may_contain(&self, key: &str) -> bool {
+        let nbits = self.bits.len() as u64 * 8;
+        probes(hash_key(key), self.k, nbits).all(|idx| self.bits[(idx / 8) as usize] & (1 << (idx % 8)) != 0)
+    }
+
+    pub fn encode(&self, buf: &mut impl Write) -> io::Result<()> {
+        buf.write_u32::<LittleEndian>(self.k)?;
+        buf.write_all(&self.bits)
+    }
+
+    pub fn decode(buf: &mut impl Read) -> io::Result<Self> {
+        let k = buf.read_u32::<LittleEndian>()?;
+        let mut bits = Vec::new();
+        buf.read_to_end(&mut bits)?;
+        if bits.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty bloom filter"));
+        }
+        Ok(BloomFilter { bits, k })
+    }
+}
+
+/// double hashing: probe_i = h1 + i * h2
+fn probes(hash: u64, k: u32, nbits: u64) -> impl Iterator<Item = u64> {
+    let h1 = hash & 0xffff_ffff;
+    let h2 = (hash >> 32) | 1;
+    (0..k as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+}
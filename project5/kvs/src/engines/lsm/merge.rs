@@ -0,0 +1,67 @@
+use crate::Result;
+use super::sstable::Entry;
+
+pub type EntryIter = Box<dyn Iterator<Item = Result<Entry>> + Send>;
+
+/// K-way merge over sorted sources. Sources are ordered newest first,
+/// so when several sources contain the same key the earliest one wins.
+pub struct MergeIter {
+    sources: Vec<EntryIter>,
+    heads: Vec<Option<Entry>>,
+    started: bool,
+}
+
+impl MergeIter {
+    pub fn new(sources: Vec<EntryIter>) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        MergeIter { sources, heads, started: false }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        if !self.started {
+            for i in 0..self.sources.len() {
+                self.advance(i)?;
+            }
+            self.started = true;
+        }
+
+        // smallest key, ties resolved to the newest source
+        let mut min: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                match min {
+                    Some(m) if self.heads[m].as_ref().unwrap().0 <= *key => {},
+                    _ => min = Some(i),
+                }
+            }
+        }
+
+        let m = match min {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        let entry = self.heads[m].take().unwrap();
+        self.advance(m)?;
+
+        // skip shadowed versions in older sources
+        for i in m + 1..self.heads.len() {
+            if self.heads[i].as_ref().map(|(k, _)| *k == entry.0).unwrap_or(false) {
+                self.advance(i)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
@@ -0,0 +1,569 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use futures::{future, Future};
+use log::{error, info};
+use serde::{Serialize, Deserialize};
+use serde_json::Deserializer;
+use tokio::sync::oneshot;
+
+use crate::{KvsEngine, EngineStats, KvError, Result, thread_pool::ThreadPool};
+use super::kv::{Command, unix_secs};
+use super::{pool_stream, KvStream, WatchStream};
+use super::watch::Notifier;
+use super::keyspace::{KeyspaceRegistry, DEFAULT_KEYSPACE};
+use self::merge::{EntryIter, MergeIter};
+use self::sstable::{SsTable, TableBuilder, TableIter, table_path};
+
+mod bloom;
+mod merge;
+mod sstable;
+
+// memtable is flushed into a level-0 table above this size
+const MEMTABLE_LIMIT: u64 = 1024 * 1024;
+// level-0 tables overlap each other, merge them down past this count
+const L0_COMPACTION_TRIGGER: usize = 4;
+const LEVEL_BASE_BYTES: u64 = 10 * 1024 * 1024;
+const LEVEL_MULTIPLIER: u64 = 10;
+const MAX_LEVELS: usize = 7;
+// compaction output is split into files of about this size
+const TARGET_FILE_SIZE: u64 = 2 * 1024 * 1024;
+
+const WAL_FILE: &str = "wal";
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Log-structured merge tree engine: writes go to a WAL and an in-memory
+/// memtable, which is flushed into immutable sorted tables organized in levels.
+#[derive(Clone)]
+pub struct LsmEngine<P: ThreadPool> {
+    // tree of the keyspace this handle operates on
+    tree: Arc<LsmTree>,
+    // one independent tree per keyspace
+    trees: Arc<KeyspaceRegistry<LsmTree>>,
+    pool: P,
+}
+
+impl<P: ThreadPool> LsmEngine<P> {
+    /// open a lsm-tree store with a given directory
+    pub fn open(dir: impl Into<PathBuf>, concurrency: usize) -> Result<Self> {
+        let trees = KeyspaceRegistry::open(dir.into(), LsmTree::open)?;
+        Ok(LsmEngine {
+            tree: trees.get(DEFAULT_KEYSPACE)?,
+            trees: Arc::new(trees),
+            pool: P::new(concurrency)?,
+        })
+    }
+}
+
+struct LsmTree {
+    memtable: Arc<RwLock<MemTable>>,
+    // current set of tables, swapped as a whole on flush/compaction
+    version: Arc<RwLock<Arc<Version>>>,
+    writer: Arc<Mutex<LsmWriter>>,
+    notifier: Notifier,
+}
+
+impl LsmTree {
+    fn open(dir: &Path) -> Result<Self> {
+        let path = Arc::new(dir.to_path_buf());
+        let manifest = Manifest::load(&path)?;
+
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut live = HashSet::new();
+        for (level, ids) in manifest.levels.iter().enumerate() {
+            for &id in ids {
+                levels[level].push(Arc::new(SsTable::open(&path, id)?));
+                live.insert(id);
+            }
+        }
+        remove_stale_tables(&path, &live)?;
+
+        // replay the wal into a fresh memtable
+        let mut memtable = MemTable::default();
+        let wal_path = path.join(WAL_FILE);
+        if wal_path.exists() {
+            let reader = BufReader::new(File::open(&wal_path)?);
+            for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+                match cmd? {
+                    Command::Set { key, value } => memtable.insert(key, Some(value)),
+                    Command::Remove { key } => memtable.insert(key, None),
+                }
+            }
+        }
+        let wal = BufWriter::new(OpenOptions::new().create(true).append(true).open(&wal_path)?);
+
+        let memtable = Arc::new(RwLock::new(memtable));
+        let version = Arc::new(RwLock::new(Arc::new(Version { levels })));
+        let notifier = Notifier::new();
+        let writer = LsmWriter {
+            path,
+            wal,
+            memtable: memtable.clone(),
+            version: version.clone(),
+            next_table_id: manifest.next_table_id,
+            compact_pointers: vec![None; MAX_LEVELS],
+            compactions: 0,
+            last_compaction: None,
+            notifier: notifier.clone(),
+        };
+
+        Ok(LsmTree {
+            memtable,
+            version,
+            writer: Arc::new(Mutex::new(writer)),
+            notifier,
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for LsmEngine<P> {
+    fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let writer = self.tree.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().set(key, value);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
+        let memtable = self.tree.memtable.clone();
+        let version = self.tree.version.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = lookup(&memtable, &version, &key);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let writer = self.tree.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().remove(key);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>)
+        -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        let writer = self.tree.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().compare_and_swap(key, expected, new);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    /// iterate in key order over a snapshot of the memtable and the tables
+    fn iter(&self) -> KvStream {
+        let memtable: Vec<(String, Option<String>)> = self.tree.memtable.read().unwrap().map.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let version = self.tree.version.read().unwrap().clone();
+
+        let mut sources: Vec<EntryIter> = vec![Box::new(memtable.into_iter().map(Ok))];
+        sources.extend(version.levels.iter().flatten().map(|t| Box::new(TableIter::new(t.clone())) as EntryIter));
+        let live = MergeIter::new(sources).filter_map(|entry| match entry {
+            Ok((key, Some(value))) => Some(Ok((key, value))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        });
+        pool_stream(self.pool.clone(), live)
+    }
+
+    fn watch(&self, prefix: String) -> WatchStream {
+        self.tree.notifier.subscribe(prefix)
+    }
+
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<EngineStats>> + Send>> {
+        let writer = self.tree.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = writer.lock().unwrap().stats();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        Ok(LsmEngine {
+            tree: self.trees.get(name)?,
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let trees = self.trees.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = trees.create(&name, LsmTree::open);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn drop_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let trees = self.trees.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = trees.remove(&name);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>> {
+        Box::pin(future::ready(Ok(self.trees.list())))
+    }
+}
+
+/// memtable first, then the tables from newest to oldest
+fn lookup(memtable: &RwLock<MemTable>, version: &RwLock<Arc<Version>>, key: &str) -> Result<Option<String>> {
+    if let Some(value) = memtable.read().unwrap().map.get(key) {
+        return Ok(value.clone());
+    }
+    // the version must be loaded after the memtable miss: a flush installs
+    // the new table before it clears the memtable
+    let version = version.read().unwrap().clone();
+    version.get(key)
+}
+
+/// sorted in-memory buffer of the latest writes, `None` marks a removed key
+#[derive(Default)]
+struct MemTable {
+    map: BTreeMap<String, Option<String>>,
+    size: u64,
+}
+
+impl MemTable {
+    fn insert(&mut self, key: String, value: Option<String>) {
+        self.size += (key.len() + value.as_ref().map(|v| v.len()).unwrap_or(0)) as u64;
+        self.map.insert(key, value);
+    }
+}
+
+/// tables of every level; level 0 is ordered newest first and may overlap,
+/// deeper levels are sorted by key and don't overlap
+struct Version {
+    levels: Vec<Vec<Arc<SsTable>>>,
+}
+
+impl Version {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        for table in self.levels[0].iter() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        for tables in self.levels[1..].iter() {
+            let idx = tables.partition_point(|t| t.last_key() < key);
+            if let Some(table) = tables.get(idx) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|t| t.size()).sum()
+    }
+}
+
+fn max_bytes_for_level(level: usize) -> u64 {
+    LEVEL_BASE_BYTES * LEVEL_MULTIPLIER.pow(level as u32 - 1)
+}
+
+/// persisted list of live tables per level
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_table_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// write to a temp file and rename, so a crash never leaves a torn manifest
+    fn store(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec(self)?)?;
+        f.sync_all()?;
+        fs::rename(tmp, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+/// delete tables left behind by a crash in the middle of a flush/compaction
+fn remove_stale_tables(dir: &Path, live: &HashSet<u64>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext == "sst").unwrap_or(false) {
+            let id = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok());
+            if let Some(id) = id {
+                if !live.contains(&id) {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct LsmWriter {
+    path: Arc<PathBuf>,
+    wal: BufWriter<File>,
+    memtable: Arc<RwLock<MemTable>>,
+    version: Arc<RwLock<Arc<Version>>>,
+    next_table_id: u64,
+    // last compacted key per level, so compaction rotates through the key space
+    compact_pointers: Vec<Option<String>>,
+    // compactions since open
+    compactions: u64,
+    last_compaction: Option<SystemTime>,
+    // told about every successful wal append
+    notifier: Notifier,
+}
+
+impl LsmWriter {
+    /// Set the value of a string key to string
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        serde_json::to_writer(&mut self.wal, &Command::Set { key: key.clone(), value: value.clone() })?;
+        self.wal.flush()?;
+        self.notifier.notify(&key, Some(&value));
+        self.memtable.write().unwrap().insert(key, Some(value));
+        self.maybe_flush()
+    }
+
+    /// remove the value of the string key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        // only existent key need to remove
+        if lookup(&self.memtable, &self.version, &key)?.is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        serde_json::to_writer(&mut self.wal, &Command::Remove { key: key.clone() })?;
+        self.wal.flush()?;
+        self.notifier.notify(&key, None);
+        self.memtable.write().unwrap().insert(key, None);
+        self.maybe_flush()
+    }
+
+    /// Replace the value of `key` with `new` if it currently is `expected`.
+    /// Holding the writer keeps other writes out between the read and the write.
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        if lookup(&self.memtable, &self.version, &key)? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if expected.is_some() => self.remove(key)?,
+            None => {},
+        }
+        Ok(true)
+    }
+
+    /// table and wal sizes; the key count is unknown without a full merge
+    pub fn stats(&self) -> Result<EngineStats> {
+        let version = self.version.read().unwrap().clone();
+        let tables: Vec<&Arc<SsTable>> = version.levels.iter().flatten().collect();
+        let wal_bytes = fs::metadata(self.path.join(WAL_FILE))?.len();
+
+        Ok(EngineStats {
+            engine: "lsm".to_owned(),
+            total_bytes: Some(tables.iter().map(|t| t.size()).sum::<u64>() + wal_bytes),
+            generations: Some(tables.len() as u64),
+            current_gen: Some(self.next_table_id),
+            last_compaction: self.last_compaction.map(unix_secs),
+            compactions: Some(self.compactions),
+            ..EngineStats::default()
+        })
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.memtable.read().unwrap().size >= MEMTABLE_LIMIT {
+            self.flush_memtable()?;
+            self.compaction()?;
+        }
+        Ok(())
+    }
+
+    /// write the memtable into a new level-0 table and start a new wal
+    fn flush_memtable(&mut self) -> Result<()> {
+        let id = self.next_table_id;
+        self.next_table_id += 1;
+
+        {
+            let memtable = self.memtable.read().unwrap();
+            let mut builder = TableBuilder::create(&self.path, id)?;
+            for (key, value) in memtable.map.iter() {
+                builder.add(key, value.as_deref())?;
+            }
+            builder.finish()?;
+        }
+
+        let table = Arc::new(SsTable::open(&self.path, id)?);
+        let mut levels = self.version.read().unwrap().levels.clone();
+        levels[0].insert(0, table);
+        self.install(levels)?;
+
+        *self.memtable.write().unwrap() = MemTable::default();
+        self.wal = BufWriter::new(File::create(self.path.join(WAL_FILE))?);
+        Ok(())
+    }
+
+    /// persist the manifest, then publish the new version to readers
+    fn install(&mut self, levels: Vec<Vec<Arc<SsTable>>>) -> Result<()> {
+        Manifest {
+            next_table_id: self.next_table_id,
+            levels: levels.iter().map(|tables| tables.iter().map(|t| t.id()).collect()).collect(),
+        }.store(&self.path)?;
+        *self.version.write().unwrap() = Arc::new(Version { levels });
+        Ok(())
+    }
+
+    /// leveled compaction: merge level-0 into level-1 once it has too many
+    /// tables, and push one table of any oversized level into the next one
+    fn compaction(&mut self) -> Result<()> {
+        loop {
+            let version = self.version.read().unwrap().clone();
+            let (level, inputs) = if version.levels[0].len() >= L0_COMPACTION_TRIGGER {
+                (0, version.levels[0].clone())
+            } else if let Some(level) = (1..MAX_LEVELS - 1).find(|&l| version.level_size(l) > max_bytes_for_level(l)) {
+                let tables = &version.levels[level];
+                let table = self.compact_pointers[level].as_ref()
+                    .and_then(|ptr| tables.iter().find(|t| t.first_key() > ptr.as_str()))
+                    .unwrap_or(&tables[0])
+                    .clone();
+                self.compact_pointers[level] = Some(table.last_key().to_owned());
+                (level, vec![table])
+            } else {
+                return Ok(());
+            };
+
+            let first = inputs.iter().map(|t| t.first_key()).min().unwrap().to_owned();
+            let last = inputs.iter().map(|t| t.last_key()).max().unwrap().to_owned();
+            let overlapping: Vec<Arc<SsTable>> = version.levels[level + 1].iter()
+                .filter(|t| t.overlaps(&first, &last))
+                .cloned()
+                .collect();
+            // nothing older can hide below, tombstones can be dropped
+            let bottom = version.levels[level + 2..].iter().all(|tables| tables.is_empty());
+
+            info!("compacting {} tables of level {} with {} tables of level {}",
+                inputs.len(), level, overlapping.len(), level + 1);
+
+            let sources: Vec<EntryIter> = inputs.iter().chain(overlapping.iter())
+                .map(|t| Box::new(TableIter::new(t.clone())) as EntryIter)
+                .collect();
+            let outputs = self.write_tables(MergeIter::new(sources), bottom)?;
+
+            let obsolete: HashSet<u64> = inputs.iter().chain(overlapping.iter()).map(|t| t.id()).collect();
+            let mut levels = version.levels.clone();
+            levels[level].retain(|t| !obsolete.contains(&t.id()));
+            levels[level + 1].retain(|t| !obsolete.contains(&t.id()));
+            levels[level + 1].extend(outputs);
+            levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+            self.install(levels)?;
+            self.compactions += 1;
+            self.last_compaction = Some(SystemTime::now());
+
+            // readers still holding the old version keep their file handles open
+            for id in obsolete {
+                fs::remove_file(table_path(&self.path, id))?;
+            }
+        }
+    }
+
+    /// write merged entries into tables of about `TARGET_FILE_SIZE`
+    fn write_tables(&mut self, entries: MergeIter, drop_tombstones: bool) -> Result<Vec<Arc<SsTable>>> {
+        let mut ids = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+            if builder.is_none() {
+                ids.push(self.next_table_id);
+                builder = Some(TableBuilder::create(&self.path, self.next_table_id)?);
+                self.next_table_id += 1;
+            }
+            let b = builder.as_mut().unwrap();
+            b.add(&key, value.as_deref())?;
+            if b.estimated_size() >= TARGET_FILE_SIZE {
+                builder.take().unwrap().finish()?;
+            }
+        }
+        if let Some(b) = builder {
+            b.finish()?;
+        }
+
+        ids.into_iter().map(|id| Ok(Arc::new(SsTable::open(&self.path, id)?))).collect()
+    }
+}
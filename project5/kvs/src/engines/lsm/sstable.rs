@@ -0,0 +1,295 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Serialize, Deserialize};
+
+use crate::{KvError, Result};
+use super::bloom::BloomFilter;
+use crate::engines::hash::hash_key;
+
+const BLOCK_SIZE: usize = 4 * 1024;
+const FOOTER_SIZE: u64 = 5 * 8;
+const TABLE_MAGIC: u64 = 0x6b76_735f_6c73_6d31;
+
+const KIND_REMOVE: u8 = 0;
+const KIND_SET: u8 = 1;
+
+/// key -> value, `None` value is a tombstone
+pub type Entry = (String, Option<String>);
+
+pub fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// location of a data block, `last_key` is the biggest key in the block
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableIndex {
+    first_key: String,
+    entries: u64,
+    blocks: Vec<BlockHandle>,
+}
+
+/// Writes sorted entries into a new sstable file.
+///
+/// layout: data blocks | index (json) | bloom filter | footer
+pub struct TableBuilder {
+    writer: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    last_key: String,
+    first_key: Option<String>,
+    entries: u64,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub fn create(dir: &Path, id: u64) -> Result<Self> {
+        let f = OpenOptions::new().create(true).write(true).truncate(true).open(table_path(dir, id))?;
+        Ok(TableBuilder {
+            writer: BufWriter::new(f),
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last_key: String::new(),
+            first_key: None,
+            entries: 0,
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    /// keys must be added in ascending order
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        self.block.write_u32::<LittleEndian>(key.len() as u32)?;
+        self.block.write_all(key.as_bytes())?;
+        match value {
+            Some(value) => {
+                self.block.write_u8(KIND_SET)?;
+                self.block.write_u32::<LittleEndian>(value.len() as u32)?;
+                self.block.write_all(value.as_bytes())?;
+            },
+            None => self.block.write_u8(KIND_REMOVE)?,
+        }
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        self.hashes.push(hash_key(key));
+        self.entries += 1;
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// bytes written so far, used to cut compaction output into files
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// write index, bloom filter and footer, then sync the file to disk
+    pub fn finish(mut self) -> Result<()> {
+        self.flush_block()?;
+
+        let index = TableIndex {
+            first_key: self.first_key.take().unwrap_or_default(),
+            entries: self.entries,
+            blocks: std::mem::take(&mut self.blocks),
+        };
+        let index_buf = serde_json::to_vec(&index)?;
+        let index_offset = self.offset;
+        self.writer.write_all(&index_buf)?;
+
+        let mut bloom_buf = Vec::new();
+        BloomFilter::from_hashes(&self.hashes).encode(&mut bloom_buf)?;
+        let bloom_offset = index_offset + index_buf.len() as u64;
+        self.writer.write_all(&bloom_buf)?;
+
+        self.writer.write_u64::<LittleEndian>(index_offset)?;
+        self.writer.write_u64::<LittleEndian>(index_buf.len() as u64)?;
+        self.writer.write_u64::<LittleEndian>(bloom_offset)?;
+        self.writer.write_u64::<LittleEndian>(bloom_buf.len() as u64)?;
+        self.writer.write_u64::<LittleEndian>(TABLE_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// An immutable sorted table on disk. Index and bloom filter stay in memory,
+/// data blocks are read on demand.
+pub struct SsTable {
+    id: u64,
+    file: Mutex<File>,
+    blocks: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    first_key: String,
+    size: u64,
+}
+
+impl SsTable {
+    pub fn open(dir: &Path, id: u64) -> Result<Self> {
+        let mut file = File::open(table_path(dir, id))?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE {
+            return Err(KvError::Corrupted(format!("sstable {} too short", id)));
+        }
+
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE))?;
+        let index_offset = file.read_u64::<LittleEndian>()?;
+        let index_len = file.read_u64::<LittleEndian>()?;
+        let bloom_offset = file.read_u64::<LittleEndian>()?;
+        let bloom_len = file.read_u64::<LittleEndian>()?;
+        if file.read_u64::<LittleEndian>()? != TABLE_MAGIC {
+            return Err(KvError::Corrupted(format!("sstable {} bad magic number", id)));
+        }
+
+        let index: TableIndex = serde_json::from_slice(&read_at(&mut file, index_offset, index_len)?)?;
+        let bloom = BloomFilter::decode(&mut Cursor::new(read_at(&mut file, bloom_offset, bloom_len)?))?;
+
+        Ok(SsTable {
+            id,
+            file: Mutex::new(file),
+            blocks: index.blocks,
+            bloom,
+            first_key: index.first_key,
+            size,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn first_key(&self) -> &str {
+        &self.first_key
+    }
+
+    pub fn last_key(&self) -> &str {
+        self.blocks.last().map(|b| b.last_key.as_str()).unwrap_or("")
+    }
+
+    /// file size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// whether the key range of the table intersects [first, last]
+    pub fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key() <= last && self.last_key() >= first
+    }
+
+    /// `None` if the table knows nothing about the key,
+    /// `Some(None)` if the key was removed
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key() || key > self.last_key() || !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // first block whose last key >= key
+        let idx = self.blocks.partition_point(|b| b.last_key.as_str() < key);
+        if idx >= self.blocks.len() {
+            return Ok(None);
+        }
+        Ok(self.read_block(idx)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v))
+    }
+
+    fn read_block(&self, idx: usize) -> Result<Vec<Entry>> {
+        let handle = &self.blocks[idx];
+        let buf = read_at(&mut self.file.lock().unwrap(), handle.offset, handle.len)?;
+        decode_block(&buf)
+    }
+}
+
+/// Scans a table block by block in key order.
+pub struct TableIter {
+    table: Arc<SsTable>,
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    pub fn new(table: Arc<SsTable>) -> Self {
+        TableIter { table, next_block: 0, entries: Vec::new().into_iter() }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn decode_block(buf: &[u8]) -> Result<Vec<Entry>> {
+    let mut cursor = Cursor::new(buf);
+    let mut entries = Vec::new();
+    while (cursor.position() as usize) < buf.len() {
+        let key = read_string(&mut cursor)?;
+        let value = match cursor.read_u8()? {
+            KIND_SET => Some(read_string(&mut cursor)?),
+            KIND_REMOVE => None,
+            kind => return Err(KvError::Corrupted(format!("unknown entry kind {}", kind))),
+        };
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+fn read_string(buf: &mut impl Read) -> Result<String> {
+    let len = buf.read_u32::<LittleEndian>()?;
+    let mut bytes = vec![0; len as usize];
+    buf.read_exact(&mut bytes).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => KvError::Corrupted("truncated block".to_owned()),
+        _ => KvError::Io(e),
+    })?;
+    Ok(String::from_utf8(bytes)?)
+}
@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use futures::{future, stream, Future, StreamExt};
+
+use crate::{KvsEngine, EngineStats, KvError, Result};
+use super::{KvStream, WatchStream};
+use super::watch::{Notifier, Notifiers};
+use super::keyspace::{check_name, DEFAULT_KEYSPACE};
+
+/// which entry goes first when the memory limit is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// least recently used
+    Lru,
+    /// least frequently used, ties broken by recency
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(KvError::StringError(format!("unknown eviction policy: {}", s))),
+        }
+    }
+}
+
+/// Non-persistent engine keeping everything in a `HashMap`, usable as a
+/// cache when a memory limit is set.
+///
+/// Operations are cheap enough to run inline, so no thread pool is involved.
+/// The memory limit is shared by all keyspaces.
+#[derive(Clone)]
+pub struct MemEngine {
+    inner: Arc<Mutex<MemInner>>,
+    // keyspace this handle operates on
+    keyspace: String,
+    notifier: Notifier,
+    notifiers: Notifiers,
+}
+
+impl MemEngine {
+    /// an unbounded in-memory store
+    pub fn new() -> Self {
+        Self::with_limit(None, EvictionPolicy::Lru)
+    }
+
+    /// bound the memory used by keys and values to `max_memory` bytes,
+    /// evicting entries by `policy` once it's exceeded
+    pub fn with_limit(max_memory: Option<u64>, policy: EvictionPolicy) -> Self {
+        let mut spaces = HashMap::new();
+        spaces.insert(DEFAULT_KEYSPACE.to_owned(), HashMap::new());
+        let notifiers = Notifiers::default();
+        MemEngine {
+            inner: Arc::new(Mutex::new(MemInner {
+                spaces,
+                order: BTreeMap::new(),
+                used: 0,
+                tick: 0,
+                max_memory,
+                policy,
+            })),
+            keyspace: DEFAULT_KEYSPACE.to_owned(),
+            notifier: notifiers.get(DEFAULT_KEYSPACE),
+            notifiers,
+        }
+    }
+}
+
+impl Default for MemEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvsEngine for MemEngine {
+    fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let mut inner = self.inner.lock().unwrap();
+        let res = inner.set(&self.keyspace, key.clone(), value.clone());
+        if res.is_ok() {
+            self.notifier.notify(&key, Some(&value));
+        }
+        Box::pin(future::ready(res))
+    }
+
+    fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
+        let res = self.inner.lock().unwrap().get(&self.keyspace, &key);
+        Box::pin(future::ready(res))
+    }
+
+    fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let mut inner = self.inner.lock().unwrap();
+        let res = inner.remove(&self.keyspace, &key);
+        if res.is_ok() {
+            self.notifier.notify(&key, None);
+        }
+        Box::pin(future::ready(res))
+    }
+
+    /// the read and the write happen under the same lock
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>)
+        -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        let mut inner = self.inner.lock().unwrap();
+        let res = inner.get(&self.keyspace, &key).and_then(|current| {
+            if current != expected {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => inner.set(&self.keyspace, key.clone(), value.clone())?,
+                None if expected.is_some() => inner.remove(&self.keyspace, &key)?,
+                None => return Ok(true),
+            }
+            self.notifier.notify(&key, new.as_deref());
+            Ok(true)
+        });
+        Box::pin(future::ready(res))
+    }
+
+    /// `total_bytes` covers all keyspaces since they share the limit
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<EngineStats>> + Send>> {
+        let inner = self.inner.lock().unwrap();
+        let res = inner.space(&self.keyspace).map(|space| EngineStats {
+            engine: "memory".to_owned(),
+            keys: Some(space.len() as u64),
+            total_bytes: Some(inner.used),
+            ..EngineStats::default()
+        });
+        Box::pin(future::ready(res))
+    }
+
+    /// iterate over a snapshot of the keys, doesn't count as an access for eviction
+    fn iter(&self) -> KvStream {
+        let keys: Vec<String> = match self.inner.lock().unwrap().space(&self.keyspace) {
+            Ok(space) => space.keys().cloned().collect(),
+            Err(e) => return Box::pin(stream::once(future::ready(Err(e)))),
+        };
+        let inner = self.inner.clone();
+        let keyspace = self.keyspace.clone();
+        Box::pin(stream::iter(keys).filter_map(move |key| {
+            let value = inner.lock().unwrap().spaces.get(&keyspace)
+                .and_then(|space| space.get(&key))
+                .map(|e| e.value.clone());
+            future::ready(value.map(|value| Ok((key, value))))
+        }))
+    }
+
+    /// evictions are not reported, only explicit sets and removes
+    fn watch(&self, prefix: String) -> WatchStream {
+        self.notifier.subscribe(prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        self.inner.lock().unwrap().space(name)?;
+        Ok(MemEngine {
+            keyspace: name.to_owned(),
+            notifier: self.notifiers.get(name),
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let res = check_name(&name).and_then(|_| {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.spaces.contains_key(&name) {
+                return Err(KvError::KeyspaceExists(name));
+            }
+            inner.spaces.insert(name, HashMap::new());
+            Ok(())
+        });
+        Box::pin(future::ready(res))
+    }
+
+    fn drop_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let res = check_name(&name).and_then(|_| self.inner.lock().unwrap().drop_space(&name));
+        Box::pin(future::ready(res))
+    }
+
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>> {
+        let mut names: Vec<String> = self.inner.lock().unwrap().spaces.keys().cloned().collect();
+        names.sort();
+        Box::pin(future::ready(Ok(names)))
+    }
+}
+
+struct MemEntry {
+    value: String,
+    // position in `order`
+    rank: (u64, u64),
+    hits: u64,
+}
+
+struct MemInner {
+    // keyspace -> key -> entry
+    spaces: HashMap<String, HashMap<String, MemEntry>>,
+    // eviction order over all keyspaces as (keyspace, key),
+    // smallest rank is evicted first
+    order: BTreeMap<(u64, u64), (String, String)>,
+    // bytes of keys and values
+    used: u64,
+    // logical clock, bumped on every access
+    tick: u64,
+    max_memory: Option<u64>,
+    policy: EvictionPolicy,
+}
+
+impl MemInner {
+    fn space(&self, keyspace: &str) -> Result<&HashMap<String, MemEntry>> {
+        self.spaces.get(keyspace).ok_or_else(|| KvError::KeyspaceNotFound(keyspace.to_owned()))
+    }
+
+    fn space_mut(&mut self, keyspace: &str) -> Result<&mut HashMap<String, MemEntry>> {
+        self.spaces.get_mut(keyspace).ok_or_else(|| KvError::KeyspaceNotFound(keyspace.to_owned()))
+    }
+
+    fn rank(&mut self, hits: u64) -> (u64, u64) {
+        self.tick += 1;
+        match self.policy {
+            EvictionPolicy::Lru => (self.tick, 0),
+            EvictionPolicy::Lfu => (hits, self.tick),
+        }
+    }
+
+    fn get(&mut self, keyspace: &str, key: &str) -> Result<Option<String>> {
+        let (old_rank, hits) = match self.space(keyspace)?.get(key) {
+            Some(entry) => (entry.rank, entry.hits + 1),
+            None => return Ok(None),
+        };
+        let rank = self.rank(hits);
+        let id = self.order.remove(&old_rank).unwrap();
+        self.order.insert(rank, id);
+
+        let entry = self.space_mut(keyspace)?.get_mut(key).unwrap();
+        entry.rank = rank;
+        entry.hits = hits;
+        Ok(Some(entry.value.clone()))
+    }
+
+    fn set(&mut self, keyspace: &str, key: String, value: String) -> Result<()> {
+        let size = (key.len() + value.len()) as u64;
+        if let Some(max) = self.max_memory {
+            if size > max {
+                return Err(KvError::ValueTooLarge);
+            }
+        }
+
+        let hits = match self.space_mut(keyspace)?.remove(&key) {
+            Some(old) => {
+                self.order.remove(&old.rank);
+                self.used -= (key.len() + old.value.len()) as u64;
+                old.hits + 1
+            },
+            None => 1,
+        };
+        self.evict(size);
+
+        let rank = self.rank(hits);
+        self.order.insert(rank, (keyspace.to_owned(), key.clone()));
+        self.space_mut(keyspace)?.insert(key, MemEntry { value, rank, hits });
+        self.used += size;
+        Ok(())
+    }
+
+    fn remove(&mut self, keyspace: &str, key: &str) -> Result<()> {
+        let entry = self.space_mut(keyspace)?.remove(key).ok_or(KvError::KeyNotFound)?;
+        self.order.remove(&entry.rank);
+        self.used -= (key.len() + entry.value.len()) as u64;
+        Ok(())
+    }
+
+    fn drop_space(&mut self, keyspace: &str) -> Result<()> {
+        let space = self.spaces.remove(keyspace)
+            .ok_or_else(|| KvError::KeyspaceNotFound(keyspace.to_owned()))?;
+        for (key, entry) in space {
+            self.order.remove(&entry.rank);
+            self.used -= (key.len() + entry.value.len()) as u64;
+        }
+        Ok(())
+    }
+
+    /// drop entries until `incoming` more bytes fit under the limit
+    fn evict(&mut self, incoming: u64) {
+        let max = match self.max_memory {
+            Some(max) => max,
+            None => return,
+        };
+        while self.used + incoming > max {
+            let (_, (keyspace, key)) = match self.order.pop_first() {
+                Some(first) => first,
+                None => return,
+            };
+            let entry = self.spaces.get_mut(&keyspace).unwrap().remove(&key).unwrap();
+            self.used -= (key.len() + entry.value.len()) as u64;
+        }
+    }
+}
@@ -1,17 +1,195 @@
-use futures::{Future};
+use futures::{future, Future, Stream, StreamExt, stream};
+use std::fmt;
 use std::pin::Pin;
+use serde::{Serialize, Deserialize};
+use tokio::sync::oneshot;
+use log::error;
+use crate::thread_pool::ThreadPool;
 pub use crate::{KvError, Result};
+use crate::Capabilities;
+
+// entries read per thread pool job when iterating
+const ITER_BATCH: usize = 64;
+
+/// stream of (key, value) pairs returned by `KvsEngine::iter`
+pub type KvStream = Pin<Box<dyn Stream<Item = Result<(String, String)>> + Send>>;
+
+/// (key, value) pairs returned by `KvsEngine::scan`
+pub type KvPairs = Vec<(String, String)>;
+
+/// values returned by `KvsEngine::get_many`, `None` for missing keys
+pub type KvValues = Vec<Option<String>>;
 
 /// Clone + Send + 'static supertraits
 pub trait KvsEngine: Clone + Send + Sync + 'static {
     fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
     fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>>;
     fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<EngineStats>> + Send>>;
+    /// Values of `keys` in the same order. Not a snapshot, writes may land
+    /// between the reads.
+    fn get_many(&self, keys: Vec<String>) -> Pin<Box<dyn Future<Output = Result<KvValues>> + Send>> {
+        let gets: Vec<_> = keys.into_iter().map(|key| self.get(key)).collect();
+        Box::pin(future::try_join_all(gets))
+    }
+
+    /// set the pairs in order, not atomic unlike `batch`
+    fn set_many(&self, pairs: KvPairs) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let engine = self.clone();
+        Box::pin(async move {
+            for (key, value) in pairs {
+                engine.set(key, value).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// stream every (key, value) pair, values are read lazily
+    fn iter(&self) -> KvStream;
+    /// subscribe to changes of keys starting with `prefix`
+    fn watch(&self, prefix: String) -> WatchStream;
+    /// a handle to the same engine operating on keyspace `name`
+    fn keyspace(&self, name: &str) -> Result<Self>;
+    fn create_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+    fn drop_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>>;
+
+    /// apply all operations or none of them
+    fn batch(&self, _ops: Vec<BatchOp>) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        Box::pin(future::ready(Err(KvError::Unsupported("batch".to_owned()))))
+    }
+
+    /// Replace the value of `key` with `new` if it currently is `expected`,
+    /// `None` standing for an absent key. Returns whether it was swapped.
+    fn compare_and_swap(&self, _key: String, _expected: Option<String>, _new: Option<String>)
+        -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        Box::pin(future::ready(Err(KvError::Unsupported("compare_and_swap".to_owned()))))
+    }
+
+    /// pairs with keys in `[start, end)` in key order, at most `limit` of them
+    fn scan(&self, _start: String, _end: Option<String>, _limit: Option<usize>)
+        -> Pin<Box<dyn Future<Output = Result<KvPairs>> + Send>> {
+        Box::pin(future::ready(Err(KvError::Unsupported("scan".to_owned()))))
+    }
+
+    /// Atomically add `delta` to the integer stored at `key`, an absent key
+    /// counting as 0. Returns the new value.
+    fn incr(&self, _key: String, _delta: i64) -> Pin<Box<dyn Future<Output = Result<i64>> + Send>> {
+        Box::pin(future::ready(Err(KvError::Unsupported("incr".to_owned()))))
+    }
+
+    /// atomically append `suffix` to the value of `key`, returns the new length
+    fn append(&self, _key: String, _suffix: String) -> Pin<Box<dyn Future<Output = Result<u64>> + Send>> {
+        Box::pin(future::ready(Err(KvError::Unsupported("append".to_owned()))))
+    }
+
+    /// the optional operations this engine implements, offered to clients
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+}
+
+/// the counter value after adding `delta` to `value`
+pub(crate) fn add_delta(value: Option<&str>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => value.parse::<i64>().map_err(|_| KvError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or(KvError::Overflow)
+}
+
+/// one write of `KvsEngine::batch`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// Drive a blocking iterator on the thread pool, `ITER_BATCH` items per job,
+/// so only one batch is held in memory at a time.
+pub(crate) fn pool_stream<P, I>(pool: P, iter: I) -> KvStream
+where
+    P: ThreadPool,
+    I: Iterator<Item = Result<(String, String)>> + Send + 'static,
+{
+    let batches = stream::unfold(Some(iter), move |iter| {
+        let pool = pool.clone();
+        async move {
+            let mut iter = iter?;
+            let (tx, rx) = oneshot::channel();
+            pool.spawn(move || {
+                let batch: Vec<_> = iter.by_ref().take(ITER_BATCH).collect();
+                if tx.send((batch, iter)).is_err() {
+                    error!("Receiving end is dropped");
+                }
+            });
+
+            let (batch, iter) = rx.await.unwrap();
+            if batch.is_empty() {
+                return None;
+            }
+            let next = if batch.len() < ITER_BATCH { None } else { Some(iter) };
+            Some((stream::iter(batch), next))
+        }
+    });
+    Box::pin(batches.flatten())
+}
+
+/// Engine statistics, fields an engine can't report are left `None`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    pub engine: String,
+    // number of live keys
+    pub keys: Option<u64>,
+    // stale bytes waiting for compaction
+    pub uncompacted: Option<u64>,
+    // bytes of data files (log files, sstables, sled size on disk, memory in use)
+    pub total_bytes: Option<u64>,
+    // number of log files / sstables
+    pub generations: Option<u64>,
+    pub current_gen: Option<u64>,
+    // unix timestamp in seconds
+    pub last_compaction: Option<u64>,
+    pub compactions: Option<u64>,
+}
+
+impl fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "engine: {}", self.engine)?;
+        let fields = [
+            ("keys", self.keys),
+            ("uncompacted", self.uncompacted),
+            ("total_bytes", self.total_bytes),
+            ("generations", self.generations),
+            ("current_gen", self.current_gen),
+            ("last_compaction", self.last_compaction),
+            ("compactions", self.compactions),
+        ];
+        for (name, value) in fields.iter() {
+            if let Some(value) = value {
+                write!(f, "\n{}: {}", name, value)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 
 mod kv;
 mod sled;
+mod lsm;
+mod memory;
+mod watch;
+mod keyspace;
+mod hash;
+mod crypto;
+mod limiter;
 
-pub use self::kv::{KvStore};
-pub use self::sled::{SledEngine};
\ No newline at end of file
+pub use self::kv::{KvStore, KvOptions};
+pub use self::sled::{SledEngine, SledOptions, SledMode};
+pub use self::lsm::{LsmEngine};
+pub use self::memory::{MemEngine, EvictionPolicy};
+pub use self::watch::{WatchEvent, WatchStream};
+pub use self::keyspace::{DEFAULT_KEYSPACE, MEMCACHE_KEYSPACE};
+pub(crate) use self::keyspace::check_client_name;
+pub use self::crypto::Cipher;
\ No newline at end of file
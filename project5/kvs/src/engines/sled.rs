@@ -1,37 +1,114 @@
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, KvError, Result};
+use crate::{KvsEngine, EngineStats, KvError, Capabilities, Result};
+use super::{add_delta, pool_stream, BatchOp, KvPairs, KvStream, WatchStream};
+use super::watch::{Notifier, Notifiers};
+use super::keyspace::{check_name, DEFAULT_KEYSPACE};
 use tokio::sync::oneshot;
 use sled::{self, Db, Tree};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use std::path::PathBuf;
 use std::pin::Pin;
-use futures::Future;
+use std::str::FromStr;
+use futures::{future, Future};
 use std::sync::Arc;
 use log::{error};
 
+/// trade-off sled optimizes its storage for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SledMode {
+    LowSpace,
+    HighThroughput,
+}
+
+impl FromStr for SledMode {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "low-space" => Ok(SledMode::LowSpace),
+            "high-throughput" => Ok(SledMode::HighThroughput),
+            _ => Err(KvError::StringError(format!("unknown sled mode: {}", s))),
+        }
+    }
+}
+
+/// options for `SledEngine::open_with`, `None` keeps sled's default
+#[derive(Debug, Clone, Default)]
+pub struct SledOptions {
+    /// bytes of the page cache
+    pub cache_capacity: Option<u64>,
+    /// Flush in the background this often instead of after every write.
+    /// Writes since the last flush may be lost on a crash.
+    pub flush_every_ms: Option<u64>,
+    /// zstd compression with the given level
+    pub compression: Option<i32>,
+    pub mode: Option<SledMode>,
+}
+
 #[derive(Clone)]
 pub struct SledEngine<P: ThreadPool> {
     db: Arc<Db>,
+    // tree of the keyspace this handle operates on
+    tree: Tree,
     pool: P,
+    // whether writes wait for a flush, off when sled flushes in the background
+    sync: bool,
+    // sled has no writer lock, concurrent writes to the same key may be
+    // numbered in a different order than sled applied them
+    notifier: Notifier,
+    notifiers: Notifiers,
 }
 
 impl<P: ThreadPool> SledEngine<P> {
     pub fn open(dir: impl Into<PathBuf>, concurrency: usize) -> Result<impl KvsEngine> {
-        let db = sled::open(dir.into())?;
+        Self::open_with(dir, concurrency, SledOptions::default())
+    }
+
+    pub fn open_with(dir: impl Into<PathBuf>, concurrency: usize, options: SledOptions) -> Result<impl KvsEngine> {
+        let mut config = sled::Config::new().path(dir.into());
+        // without it sled keeps its own background flush, on top of the flush after every write
+        if let Some(every_ms) = options.flush_every_ms {
+            config = config.flush_every_ms(Some(every_ms));
+        }
+        if let Some(capacity) = options.cache_capacity {
+            config = config.cache_capacity(capacity);
+        }
+        if let Some(level) = options.compression {
+            config = config.use_compression(true).compression_factor(level);
+        }
+        if let Some(mode) = options.mode {
+            config = config.mode(match mode {
+                SledMode::LowSpace => sled::Mode::LowSpace,
+                SledMode::HighThroughput => sled::Mode::HighThroughput,
+            });
+        }
+
+        let db = config.open()?;
+        let notifiers = Notifiers::default();
         Ok(SledEngine {
+            tree: (*db).clone(),
             db: Arc::new(db),
             pool: P::new(concurrency)?,
+            sync: options.flush_every_ms.is_none(),
+            notifier: notifiers.get(DEFAULT_KEYSPACE),
+            notifiers,
         })
     }
 }
 
 impl<P: ThreadPool> KvsEngine for SledEngine<P> {
     fn set(&self, key: String, value: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        let tree = self.db.clone();
+        let tree = self.tree.clone();
+        let sync = self.sync;
+        let notifier = self.notifier.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = (|| {
-                tree.insert(key, value.into_bytes()).map(|_| ())?;
-                tree.flush()?;
+                tree.insert(key.as_str(), value.as_bytes()).map(|_| ())?;
+                if sync {
+                    tree.flush()?;
+                }
+                notifier.notify(&key, Some(&value));
                 Ok(())
             })();
 
@@ -48,7 +125,7 @@ impl<P: ThreadPool> KvsEngine for SledEngine<P> {
     }
 
     fn get(&self, key: String) -> Pin<Box<dyn Future<Output = Result<Option<String>>> + Send>> {
-        let db = self.db.clone();
+        let db = self.tree.clone();
         // if let Some(vec) = tree.get(key)? {
         //     let v = String::from_utf8((*vec).try_into().unwrap())
         //         .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid data"))?;
@@ -76,12 +153,17 @@ impl<P: ThreadPool> KvsEngine for SledEngine<P> {
     }
 
     fn remove(&self, key: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
-        let tree = self.db.clone();
+        let tree = self.tree.clone();
+        let sync = self.sync;
+        let notifier = self.notifier.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = (|| {
-                tree.remove(key)?.ok_or(KvError::KeyNotFound)?;
-                tree.flush()?;
+                tree.remove(key.as_str())?.ok_or(KvError::KeyNotFound)?;
+                if sync {
+                    tree.flush()?;
+                }
+                notifier.notify(&key, None);
                 Ok(())
             })();
 
@@ -96,4 +178,326 @@ impl<P: ThreadPool> KvsEngine for SledEngine<P> {
             }
         )
     }
-}
\ No newline at end of file
+
+    fn stats(&self) -> Pin<Box<dyn Future<Output = Result<EngineStats>> + Send>> {
+        let db = self.db.clone();
+        let tree = self.tree.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                Ok(EngineStats {
+                    engine: "sled".to_owned(),
+                    keys: Some(tree.len() as u64),
+                    total_bytes: Some(db.size_on_disk()?),
+                    ..EngineStats::default()
+                })
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    /// iterate in key order
+    fn iter(&self) -> KvStream {
+        let iter = self.tree.iter().map(|res| {
+            let (key, value) = res?;
+            Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+        });
+        pool_stream(self.pool.clone(), iter)
+    }
+
+    fn watch(&self, prefix: String) -> WatchStream {
+        self.notifier.subscribe(prefix)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        let tree = if name == DEFAULT_KEYSPACE {
+            (**self.db).clone()
+        } else if self.db.tree_names().iter().any(|n| n == name.as_bytes()) {
+            self.db.open_tree(name)?
+        } else {
+            return Err(KvError::KeyspaceNotFound(name.to_owned()));
+        };
+        Ok(SledEngine {
+            tree,
+            notifier: self.notifiers.get(name),
+            ..self.clone()
+        })
+    }
+
+    fn create_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let db = self.db.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                check_name(&name)?;
+                if db.tree_names().iter().any(|n| n == name.as_bytes()) {
+                    return Err(KvError::KeyspaceExists(name));
+                }
+                db.open_tree(&name)?;
+                db.flush()?;
+                Ok(())
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn drop_keyspace(&self, name: String) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let db = self.db.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                check_name(&name)?;
+                if !db.drop_tree(&name)? {
+                    return Err(KvError::KeyspaceNotFound(name));
+                }
+                Ok(())
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn list_keyspaces(&self) -> Pin<Box<dyn Future<Output = Result<Vec<String>>> + Send>> {
+        let default_name = self.db.name();
+        let res = self.db.tree_names().into_iter()
+            .map(|name| if name == default_name {
+                Ok(DEFAULT_KEYSPACE.to_owned())
+            } else {
+                Ok(String::from_utf8(name.to_vec())?)
+            })
+            .collect::<Result<Vec<String>>>()
+            .map(|mut names| {
+                names.sort();
+                names
+            });
+        Box::pin(future::ready(res))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::BATCH | Capabilities::SCAN
+    }
+
+    /// runs as a sled transaction, removing an absent key aborts it
+    fn batch(&self, ops: Vec<BatchOp>) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let tree = self.tree.clone();
+        let sync = self.sync;
+        let notifier = self.notifier.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                tree.transaction(|tx_tree| {
+                    for op in ops.iter() {
+                        match op {
+                            BatchOp::Set { key, value } => {
+                                tx_tree.insert(key.as_str(), value.as_bytes())?;
+                            },
+                            BatchOp::Remove { key } => {
+                                if tx_tree.remove(key.as_str())?.is_none() {
+                                    return Err(ConflictableTransactionError::Abort(KvError::KeyNotFound));
+                                }
+                            },
+                        }
+                    }
+                    Ok(())
+                }).map_err(|e| match e {
+                    TransactionError::Abort(e) => e,
+                    TransactionError::Storage(e) => KvError::Sled(e),
+                })?;
+                if sync {
+                    tree.flush()?;
+                }
+                for op in ops.iter() {
+                    match op {
+                        BatchOp::Set { key, value } => notifier.notify(key, Some(value)),
+                        BatchOp::Remove { key } => notifier.notify(key, None),
+                    }
+                }
+                Ok(())
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>)
+        -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> {
+        let tree = self.tree.clone();
+        let sync = self.sync;
+        let notifier = self.notifier.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                let swapped = tree.compare_and_swap(
+                    key.as_str(),
+                    expected.as_ref().map(|v| v.as_bytes()),
+                    new.as_ref().map(|v| v.as_bytes()),
+                )?.is_ok();
+                if swapped {
+                    if sync {
+                        tree.flush()?;
+                    }
+                    notifier.notify(&key, new.as_deref());
+                }
+                Ok(swapped)
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn scan(&self, start: String, end: Option<String>, limit: Option<usize>)
+        -> Pin<Box<dyn Future<Output = Result<KvPairs>> + Send>> {
+        let tree = self.tree.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let range = match &end {
+                // an inverted range would panic in sled
+                Some(end) if end.as_str() <= start.as_str() => tree.range(start.as_str()..start.as_str()),
+                Some(end) => tree.range(start.as_str()..end.as_str()),
+                None => tree.range(start.as_str()..),
+            };
+            let res = range
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|res| {
+                    let (key, value) = res?;
+                    Ok((String::from_utf8(key.to_vec())?, String::from_utf8(value.to_vec())?))
+                })
+                .collect::<Result<Vec<_>>>();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Pin<Box<dyn Future<Output = Result<i64>> + Send>> {
+        let tree = self.tree.clone();
+        let sync = self.sync;
+        let notifier = self.notifier.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                let value = update(&tree, &key, |old| {
+                    add_delta(old.as_deref(), delta).map(|value| value.to_string())
+                })?;
+                if sync {
+                    tree.flush()?;
+                }
+                notifier.notify(&key, Some(&value));
+                Ok(value.parse().unwrap())
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+
+    fn append(&self, key: String, suffix: String) -> Pin<Box<dyn Future<Output = Result<u64>> + Send>> {
+        let tree = self.tree.clone();
+        let sync = self.sync;
+        let notifier = self.notifier.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                let value = update(&tree, &key, |old| Ok(old.unwrap_or_default() + &suffix))?;
+                if sync {
+                    tree.flush()?;
+                }
+                notifier.notify(&key, Some(&value));
+                Ok(value.len() as u64)
+            })();
+
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+
+        Box::pin(
+            async move {
+                rx.await.unwrap()
+            }
+        )
+    }
+}
+
+/// Replace the value of `key` by `f` of the current one with `update_and_fetch`,
+/// which retries `f` if another write came in between. The value is left
+/// alone if `f` fails.
+fn update(tree: &Tree, key: &str, mut f: impl FnMut(Option<String>) -> Result<String>) -> Result<String> {
+    let mut failed = None;
+    let new = tree.update_and_fetch(key, |old| {
+        let res = old
+            .map(|value| String::from_utf8(value.to_vec()).map_err(KvError::from))
+            .transpose()
+            .and_then(&mut f);
+        match res {
+            Ok(value) => {
+                failed = None;
+                Some(value.into_bytes())
+            },
+            Err(e) => {
+                failed = Some(e);
+                old.map(|value| value.to_vec())
+            },
+        }
+    })?;
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(String::from_utf8(new.map(|value| value.to_vec()).unwrap_or_default())?),
+    }
+}
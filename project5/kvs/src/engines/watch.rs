@@ -0,0 +1,93 @@
+use std::fmt;
+use std::pin::Pin;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use futures::{Stream, stream};
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::{KvError, Result};
+
+// events buffered per watcher before it starts lagging
+const WATCH_CAPACITY: usize = 1024;
+
+/// stream of changes returned by `KvsEngine::watch`
+pub type WatchStream = Pin<Box<dyn Stream<Item = Result<WatchEvent>> + Send>>;
+
+/// A change of one key, `value` is `None` when the key was removed.
+/// `seq` increases by one for every write since the engine was opened.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub seq: u64,
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{} set {} {}", self.seq, self.key, value),
+            None => write!(f, "{} rm {}", self.seq, self.key),
+        }
+    }
+}
+
+/// Fans out successful writes to watchers. Engines call `notify` while they
+/// still hold their write lock, so sequence numbers follow the write order.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    tx: broadcast::Sender<WatchEvent>,
+    seq: Arc<AtomicU64>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(WATCH_CAPACITY);
+        Notifier { tx, seq: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn notify(&self, key: &str, value: Option<&str>) {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        // nobody is watching
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.tx.send(WatchEvent {
+            seq,
+            key: key.to_owned(),
+            value: value.map(|v| v.to_owned()),
+        });
+    }
+
+    /// changes of keys starting with `prefix`, from now on
+    pub fn subscribe(&self, prefix: String) -> WatchStream {
+        let rx = self.tx.subscribe();
+        Box::pin(stream::unfold(rx, move |mut rx| {
+            let prefix = prefix.clone();
+            async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) if event.key.starts_with(&prefix) => return Some((Ok(event), rx)),
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(n)) => return Some((Err(KvError::WatchLagged(n)), rx)),
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// one `Notifier` per keyspace, for engines that keep their keyspaces in one store
+#[derive(Clone, Default)]
+pub(crate) struct Notifiers(Arc<Mutex<HashMap<String, Notifier>>>);
+
+impl Notifiers {
+    pub fn get(&self, keyspace: &str) -> Notifier {
+        self.0.lock().unwrap()
+            .entry(keyspace.to_owned())
+            .or_insert_with(Notifier::new)
+            .clone()
+    }
+}
@@ -5,6 +5,8 @@ use rayon;
 use std::io;
 use std::string::FromUtf8Error;
 
+use crate::Permission;
+
 #[derive(Fail, Debug)]
 pub enum KvError {
     #[fail(display = "Io Error: {}", _0)]
@@ -22,6 +24,54 @@ pub enum KvError {
     #[fail(display = "Reader not found")]
     ReaderNotFound,
 
+    #[fail(display = "corrupted data: {}", _0)]
+    Corrupted(String),
+
+    #[fail(display = "encryption error: {}", _0)]
+    Encryption(String),
+
+    #[fail(display = "value exceeds the memory limit")]
+    ValueTooLarge,
+
+    #[fail(display = "watcher lagged behind, {} events dropped", _0)]
+    WatchLagged(u64),
+
+    #[fail(display = "Keyspace not found: {}", _0)]
+    KeyspaceNotFound(String),
+
+    #[fail(display = "Keyspace already exists: {}", _0)]
+    KeyspaceExists(String),
+
+    #[fail(display = "invalid keyspace name: {}", _0)]
+    InvalidKeyspace(String),
+
+    #[fail(display = "unsupported operation: {}", _0)]
+    Unsupported(String),
+
+    #[fail(display = "value is not an integer")]
+    NotAnInteger,
+
+    #[fail(display = "increment would overflow")]
+    Overflow,
+
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
+
+    #[fail(display = "handshake failed: {}", _0)]
+    Handshake(String),
+
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+
+    #[fail(display = "authentication required")]
+    Unauthenticated,
+
+    #[fail(display = "authentication failed")]
+    AuthFailed,
+
+    #[fail(display = "permission denied: {} may not {} {}", user, op, resource)]
+    PermissionDenied { user: String, op: Permission, resource: String },
+
     #[fail(display = "utf8 error")]
     Utf8(#[cause] FromUtf8Error),
 
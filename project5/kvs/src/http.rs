@@ -0,0 +1,327 @@
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::oneshot::Receiver;
+use log::error;
+
+use crate::{KvsEngine, KvError, Result};
+use crate::engines::check_client_name;
+
+// request line plus headers
+const MAX_HEAD: usize = 16 * 1024;
+// bodies hold a single value, see `HttpServer::with_max_body`
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+/// A JSON gateway to the engine over HTTP/1.1.
+///
+/// - `GET /kv/{key}` answers `{"key": .., "value": ..}`
+/// - `PUT /kv/{key}` takes `{"value": ..}`
+/// - `DELETE /kv/{key}`
+/// - `GET /health` and `GET /stats`
+///
+/// Keys are percent-decoded, `?keyspace=name` selects a keyspace, any but
+/// the memcached listener's. Errors come back as `{"error": ..}`.
+pub struct HttpServer<E: KvsEngine> {
+    engine: E,
+    // larger request bodies are refused with 413
+    max_body: usize,
+}
+
+impl<E: KvsEngine> HttpServer<E> {
+    pub fn new(engine: E) -> Self {
+        HttpServer { engine, max_body: DEFAULT_MAX_BODY }
+    }
+
+    /// accept request bodies of up to `bytes`, 1 MiB by default
+    pub fn with_max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    pub async fn run<A: ToSocketAddrs>(&mut self, addr: A, rx: Receiver<()>) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+
+        tokio::select! {
+            _ = async move {
+                loop {
+                    let (socket, addr) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            error!("accept failed: {}", e);
+                            continue;
+                        },
+                    };
+                    let engine = self.engine.clone();
+                    let max_body = self.max_body;
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(engine, socket, max_body).await {
+                            error!("HTTP connection from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+            } => {}
+            _ = rx => {}
+        }
+
+        Ok(())
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct HttpResponse {
+    status: u16,
+    body: Option<serde_json::Value>,
+}
+
+impl HttpResponse {
+    fn ok(body: serde_json::Value) -> Self {
+        HttpResponse { status: 200, body: Some(body) }
+    }
+
+    fn no_content() -> Self {
+        HttpResponse { status: 204, body: None }
+    }
+
+    fn error(status: u16, msg: impl Into<String>) -> Self {
+        HttpResponse { status, body: Some(json!({ "error": msg.into() })) }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+/// answer requests until the client asks to close or hangs up
+async fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream, max_body: usize) -> Result<()> {
+    let (read_half, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    loop {
+        let req = match read_request(&mut reader, &mut writer, max_body).await {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(KvError::Protocol(msg)) => {
+                write_response(&mut writer, HttpResponse::error(400, msg), false).await?;
+                return Ok(());
+            },
+            Err(KvError::ValueTooLarge) => {
+                let msg = format!("body larger than {} bytes", max_body);
+                write_response(&mut writer, HttpResponse::error(413, msg), false).await?;
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        let keep_alive = req.keep_alive;
+        let resp = route(&engine, req).await;
+        write_response(&mut writer, resp, keep_alive).await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+async fn route<E: KvsEngine>(engine: &E, req: HttpRequest) -> HttpResponse {
+    let engine = match query_param(req.query.as_deref(), "keyspace") {
+        Some(name) => match check_client_name(&name).and_then(|_| engine.keyspace(&name)) {
+            Ok(engine) => engine,
+            Err(e) => return error_response(e),
+        },
+        None => engine.clone(),
+    };
+
+    let key = match req.path.strip_prefix("/kv/") {
+        Some(key) if !key.is_empty() => match percent_decode(key) {
+            Some(key) => Some(key),
+            None => return HttpResponse::error(400, "invalid percent-encoding in key"),
+        },
+        _ => None,
+    };
+
+    match (req.method.as_str(), req.path.as_str(), key) {
+        ("GET", "/health", _) => HttpResponse::ok(json!({ "status": "ok" })),
+        ("GET", "/stats", _) => match engine.stats().await {
+            Ok(stats) => HttpResponse::ok(json!(stats)),
+            Err(e) => error_response(e),
+        },
+        ("GET", _, Some(key)) => match engine.get(key.clone()).await {
+            Ok(Some(value)) => HttpResponse::ok(json!({ "key": key, "value": value })),
+            Ok(None) => error_response(KvError::KeyNotFound),
+            Err(e) => error_response(e),
+        },
+        ("PUT", _, Some(key)) => {
+            let body: PutBody = match serde_json::from_slice(&req.body) {
+                Ok(body) => body,
+                Err(e) => return HttpResponse::error(400, format!("expected {{\"value\": string}}: {}", e)),
+            };
+            match engine.set(key, body.value).await {
+                Ok(()) => HttpResponse::no_content(),
+                Err(e) => error_response(e),
+            }
+        },
+        ("DELETE", _, Some(key)) => match engine.remove(key).await {
+            Ok(()) => HttpResponse::no_content(),
+            Err(e) => error_response(e),
+        },
+        (_, "/health", _) | (_, "/stats", _) | (_, _, Some(_)) => HttpResponse::error(405, "method not allowed"),
+        _ => HttpResponse::error(404, "not found"),
+    }
+}
+
+fn error_response(e: KvError) -> HttpResponse {
+    let status = match e {
+        KvError::KeyNotFound | KvError::KeyspaceNotFound(_) => 404,
+        KvError::InvalidKeyspace(_) | KvError::ValueTooLarge => 400,
+        KvError::Unsupported(_) => 501,
+        _ => 500,
+    };
+    HttpResponse::error(status, e.to_string())
+}
+
+/// the next request, `None` if the client closed the connection in between
+async fn read_request<R, W>(reader: &mut BufReader<R>, writer: &mut W, max_body: usize) -> Result<Option<HttpRequest>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    loop {
+        let before = head.len();
+        let n = (&mut *reader).take((MAX_HEAD - head.len()) as u64 + 1).read_until(b'\n', &mut head).await?;
+        if n == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(KvError::Protocol("connection closed mid request".to_owned()));
+        }
+        if head.len() > MAX_HEAD {
+            return Err(KvError::Protocol("request head too large".to_owned()));
+        }
+        // an empty line ends the head, blank lines before a request are skipped
+        let line = &head[before..];
+        if line == b"\r\n" || line == b"\n" {
+            if before == 0 {
+                head.clear();
+                continue;
+            }
+            break;
+        }
+    }
+
+    let head = String::from_utf8(head).map_err(|_| KvError::Protocol("request head is not UTF-8".to_owned()))?;
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(KvError::Protocol("malformed request line".to_owned())),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(KvError::Protocol(format!("unsupported version {}", version)));
+    }
+
+    let mut content_length = 0;
+    let mut keep_alive = version != "HTTP/1.0";
+    let mut expect_continue = false;
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| KvError::Protocol("malformed header".to_owned()))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.parse::<usize>()
+                    .map_err(|_| KvError::Protocol("invalid content-length".to_owned()))?;
+            },
+            "transfer-encoding" => return Err(KvError::Protocol("chunked bodies are not supported".to_owned())),
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            "expect" if value.eq_ignore_ascii_case("100-continue") => expect_continue = true,
+            _ => {},
+        }
+    }
+    if content_length > max_body {
+        return Err(KvError::ValueTooLarge);
+    }
+
+    if expect_continue && content_length > 0 {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+    // grows with what actually arrives, not with what the header claims
+    let mut body = Vec::new();
+    let n = (&mut *reader).take(content_length as u64).read_to_end(&mut body).await?;
+    if n < content_length {
+        return Err(KvError::Protocol("connection closed mid body".to_owned()));
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    };
+    Ok(Some(HttpRequest { method: method.to_owned(), path, query, body, keep_alive }))
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, resp: HttpResponse, keep_alive: bool) -> Result<()> {
+    let body = match &resp.body {
+        Some(body) => serde_json::to_vec(body)?,
+        None => Vec::new(),
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nConnection: {}\r\n",
+        resp.status,
+        reason(resp.status),
+        if keep_alive { "keep-alive" } else { "close" },
+    );
+    if resp.body.is_some() {
+        head.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        501 => "Not Implemented",
+        _ => "Internal Server Error",
+    }
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+/// decode `%XX` escapes, `None` if they're malformed or not UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
@@ -1,11 +1,19 @@
 #![feature(type_alias_impl_trait)]
 
-pub use engines::{KvStore, SledEngine, KvsEngine};
+pub use engines::{KvStore, KvOptions, SledEngine, SledOptions, SledMode, BatchOp, LsmEngine, MemEngine, EvictionPolicy, KvsEngine, EngineStats, KvStream, KvPairs, KvValues, WatchEvent, WatchStream, DEFAULT_KEYSPACE, MEMCACHE_KEYSPACE, Cipher};
 // pub use network::{Request, GetResponse, SetResponse, RemoveResponse, Protocol};
 pub use error::{KvError, Result};
-pub use client::{Client, SymmetricalReader, SymmetricalWriter};
+pub use client::{Client, ConnectOptions, ConnReader, ConnWriter, SymmetricalReader, SymmetricalWriter};
 pub use server::{Server};
-pub use common::{Request, Response};
+pub use resp::RespServer;
+pub use codec::{Codec, WireFormat};
+pub use tls::{ServerTls, ClientTls};
+pub use auth::{Authenticator, Credentials, hash_password};
+pub use acl::{Acl, Permission};
+pub use memcache::MemcacheServer;
+pub use http::HttpServer;
+pub use pool::{ClientPool, PoolOptions, PooledClient};
+pub use common::{Request, Response, Tagged, Hello, HelloReply, Capabilities, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
 
 mod common;
 mod engines;
@@ -13,4 +21,12 @@ mod network;
 mod error;
 mod client;
 mod server;
+mod resp;
+mod codec;
+mod tls;
+mod auth;
+mod acl;
+mod memcache;
+mod http;
+mod pool;
 pub mod thread_pool;
\ No newline at end of file
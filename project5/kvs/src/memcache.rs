@@ -0,0 +1,369 @@
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::oneshot::Receiver;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use log::{error, info};
+
+use crate::{KvsEngine, KvError, Result, MEMCACHE_KEYSPACE};
+
+// memcached's own limits
+const MAX_LINE: usize = 2048;
+const MAX_KEY: usize = 250;
+const MAX_DATA: usize = 64 * 1024 * 1024;
+// larger relative expiry times are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+const FORMAT_ERROR: &str = "CLIENT_ERROR bad command line format";
+
+/// Speaks the memcached text protocol.
+///
+/// get, gets, set, add, replace, cas, delete, incr, decr, version and quit
+/// are understood. Items live in their own keyspace, `MEMCACHE_KEYSPACE`,
+/// created on start and refused by the other listeners, with flags, expiry
+/// time and cas unique kept in front of the value. Expired items are hidden
+/// but only removed when overwritten.
+/// add, replace, cas and the counters are built on `compare_and_swap`, which
+/// every engine of this crate has, `run` refuses engines without it. Data
+/// has to be UTF-8.
+pub struct MemcacheServer<E: KvsEngine> {
+    engine: E,
+    // the next cas unique, shared by all connections
+    cas_ids: Arc<AtomicU64>,
+}
+
+impl<E: KvsEngine> MemcacheServer<E> {
+    pub fn new(engine: E) -> Self {
+        // start from the clock so uniques aren't reused after a restart
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_micros() as u64);
+        MemcacheServer { engine, cas_ids: Arc::new(AtomicU64::new(start)) }
+    }
+
+    /// Fails without binding if the engine has no `compare_and_swap`.
+    pub async fn run<A: ToSocketAddrs>(&mut self, addr: A, rx: Receiver<()>) -> Result<()> {
+        match self.engine.create_keyspace(MEMCACHE_KEYSPACE.to_owned()).await {
+            Ok(()) | Err(KvError::KeyspaceExists(_)) => {},
+            Err(e) => return Err(e),
+        }
+        let engine = self.engine.keyspace(MEMCACHE_KEYSPACE)?;
+        // memcached keys are never empty, so this swap can't match and write
+        if let Err(KvError::Unsupported(_)) = engine.compare_and_swap(String::new(), Some(String::new()), None).await {
+            return Err(KvError::Unsupported("the memcached listener needs compare_and_swap".to_owned()));
+        }
+        let listener = TcpListener::bind(addr).await?;
+
+        tokio::select! {
+            _ = async move {
+                loop {
+                    let (socket, addr) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            error!("accept failed: {}", e);
+                            continue;
+                        },
+                    };
+                    let engine = engine.clone();
+                    let cas_ids = self.cas_ids.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(engine, cas_ids, socket).await {
+                            error!("memcached connection from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+            } => {}
+            _ = rx => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// answer commands in order
+async fn handle_connection<E: KvsEngine>(engine: E, cas_ids: Arc<AtomicU64>, stream: TcpStream) -> Result<()> {
+    let mut framed = Framed::new(stream, MemcacheCodec);
+    while let Some(cmd) = framed.next().await {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(KvError::Protocol(msg)) => {
+                // the data block of a malformed command can't be skipped
+                framed.send(format!("CLIENT_ERROR {}\r\n", msg)).await?;
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        if cmd.args.first().map(String::as_str) == Some("quit") {
+            info!("memcached client quit");
+            return Ok(());
+        }
+        if let Some(reply) = execute(&engine, &cas_ids, cmd).await {
+            framed.send(reply).await?;
+        }
+    }
+    Ok(())
+}
+
+/// the reply to a command, `None` if the client asked for none
+async fn execute<E: KvsEngine>(engine: &E, cas_ids: &AtomicU64, cmd: Command) -> Option<String> {
+    let Command { mut args, data } = cmd;
+    if args.is_empty() {
+        return Some("ERROR\r\n".to_owned());
+    }
+    let name = args.remove(0);
+    // get takes any number of keys, one of them may be called noreply
+    let noreply = !name.starts_with("get") && args.len() > 1 && args.last().map(String::as_str) == Some("noreply");
+    if noreply {
+        args.pop();
+    }
+
+    let reply = run(engine, cas_ids, &name, args, data).await
+        .unwrap_or_else(|e| format!("SERVER_ERROR {}", e.to_string().replace(['\r', '\n'], " ")));
+    // errors are sent regardless
+    if noreply && !reply.contains("ERROR") {
+        return None;
+    }
+    Some(reply + "\r\n")
+}
+
+async fn run<E: KvsEngine>(engine: &E, cas_ids: &AtomicU64, name: &str, args: Vec<String>, data: Option<Vec<u8>>) -> Result<String> {
+    if args.first().is_some_and(|key| key.len() > MAX_KEY) {
+        return Ok(FORMAT_ERROR.to_owned());
+    }
+
+    match (name, args.as_slice()) {
+        ("get", keys) | ("gets", keys) if !keys.is_empty() => retrieve(engine, keys.to_vec(), name == "gets").await,
+        ("set", [key, flags, exptime, _])
+            | ("add", [key, flags, exptime, _])
+            | ("replace", [key, flags, exptime, _])
+            | ("cas", [key, flags, exptime, _, _]) => {
+            let unique = match args.get(4).map(|unique| unique.parse::<u64>()) {
+                Some(Ok(unique)) => Some(unique),
+                Some(Err(_)) => return Ok(FORMAT_ERROR.to_owned()),
+                None => None,
+            };
+            let (flags, exptime) = match (flags.parse::<u32>(), exptime.parse::<i64>()) {
+                (Ok(flags), Ok(exptime)) => (flags, exptime),
+                _ => return Ok(FORMAT_ERROR.to_owned()),
+            };
+            let data = match String::from_utf8(data.unwrap_or_default()) {
+                Ok(data) => data,
+                Err(_) => return Ok("CLIENT_ERROR data must be valid UTF-8".to_owned()),
+            };
+            let item = Item { flags, expires: expires_at(exptime), cas: cas_ids.fetch_add(1, Ordering::Relaxed), data };
+            let mode = match (name, unique) {
+                ("add", _) => Mode::Add,
+                ("replace", _) => Mode::Replace,
+                ("cas", Some(unique)) => Mode::Cas(unique),
+                _ => Mode::Set,
+            };
+            store(engine, key.clone(), item, mode).await
+        },
+        ("delete", [key]) => delete(engine, key.clone()).await,
+        ("incr", [key, delta]) | ("decr", [key, delta]) => match delta.parse::<u64>() {
+            Ok(delta) => counter(engine, cas_ids, key.clone(), delta, name == "incr").await,
+            Err(_) => Ok("CLIENT_ERROR invalid numeric delta argument".to_owned()),
+        },
+        ("version", []) => Ok(format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+        ("get", _) | ("gets", _) | ("set", _) | ("add", _) | ("replace", _) | ("cas", _)
+            | ("delete", _) | ("incr", _) | ("decr", _) | ("version", _) => Ok(FORMAT_ERROR.to_owned()),
+        _ => Ok("ERROR".to_owned()),
+    }
+}
+
+/// `VALUE` lines for the live items among `keys`
+async fn retrieve<E: KvsEngine>(engine: &E, keys: Vec<String>, with_cas: bool) -> Result<String> {
+    let values = engine.get_many(keys.clone()).await?;
+    let mut reply = String::new();
+    for (key, value) in keys.iter().zip(values) {
+        let item = match live(value.as_deref())? {
+            Some(item) => item,
+            None => continue,
+        };
+        reply.push_str(&format!("VALUE {} {} {}", key, item.flags, item.data.len()));
+        if with_cas {
+            reply.push_str(&format!(" {}", item.cas));
+        }
+        reply.push_str(&format!("\r\n{}\r\n", item.data));
+    }
+    reply.push_str("END");
+    Ok(reply)
+}
+
+/// how a storage command treats the current item
+#[derive(Clone, Copy)]
+enum Mode {
+    Set,
+    // only if there's no live item
+    Add,
+    // only if there's a live item
+    Replace,
+    // only if the live item has this cas unique
+    Cas(u64),
+}
+
+async fn store<E: KvsEngine>(engine: &E, key: String, item: Item, mode: Mode) -> Result<String> {
+    let new = item.encode();
+    if let Mode::Set = mode {
+        engine.set(key, new).await?;
+        return Ok("STORED".to_owned());
+    }
+    // retried until the item didn't change between the read and the swap
+    loop {
+        let current = engine.get(key.clone()).await?;
+        let reply = match (mode, live(current.as_deref())?) {
+            (Mode::Add, Some(_)) | (Mode::Replace, None) => Some("NOT_STORED"),
+            (Mode::Cas(_), None) => Some("NOT_FOUND"),
+            (Mode::Cas(unique), Some(item)) if item.cas != unique => Some("EXISTS"),
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            return Ok(reply.to_owned());
+        }
+        if engine.compare_and_swap(key.clone(), current, Some(new.clone())).await? {
+            return Ok("STORED".to_owned());
+        }
+    }
+}
+
+async fn delete<E: KvsEngine>(engine: &E, key: String) -> Result<String> {
+    let current = engine.get(key.clone()).await?;
+    if live(current.as_deref())?.is_none() {
+        return Ok("NOT_FOUND".to_owned());
+    }
+    match engine.remove(key).await {
+        Ok(()) => Ok("DELETED".to_owned()),
+        // removed by someone else since the read
+        Err(KvError::KeyNotFound) => Ok("NOT_FOUND".to_owned()),
+        Err(e) => Err(e),
+    }
+}
+
+/// incr wraps around at 2^64, decr stops at 0
+async fn counter<E: KvsEngine>(engine: &E, cas_ids: &AtomicU64, key: String, delta: u64, incr: bool) -> Result<String> {
+    loop {
+        let current = engine.get(key.clone()).await?;
+        let mut item = match live(current.as_deref())? {
+            Some(item) => item,
+            None => return Ok("NOT_FOUND".to_owned()),
+        };
+        let value = match item.data.parse::<u64>() {
+            Ok(value) if incr => value.wrapping_add(delta),
+            Ok(value) => value.saturating_sub(delta),
+            Err(_) => return Ok("CLIENT_ERROR cannot increment or decrement non-numeric value".to_owned()),
+        };
+        item.data = value.to_string();
+        item.cas = cas_ids.fetch_add(1, Ordering::Relaxed);
+        if engine.compare_and_swap(key.clone(), current, Some(item.encode())).await? {
+            return Ok(item.data);
+        }
+    }
+}
+
+/// a value with the metadata memcached keeps for it
+struct Item {
+    flags: u32,
+    // unix time in seconds, 0 never expires
+    expires: u64,
+    cas: u64,
+    data: String,
+}
+
+impl Item {
+    fn encode(&self) -> String {
+        format!("{}:{}:{}:{}", self.flags, self.expires, self.cas, self.data)
+    }
+
+    fn decode(raw: &str) -> Result<Item> {
+        let mut fields = raw.splitn(4, ':');
+        let item = (|| Some(Item {
+            flags: fields.next()?.parse().ok()?,
+            expires: fields.next()?.parse().ok()?,
+            cas: fields.next()?.parse().ok()?,
+            data: fields.next()?.to_owned(),
+        }))();
+        item.ok_or_else(|| KvError::StringError("malformed memcached item".to_owned()))
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires <= unix_now()
+    }
+}
+
+/// the stored item if it exists and hasn't expired
+fn live(raw: Option<&str>) -> Result<Option<Item>> {
+    Ok(raw.map(Item::decode).transpose()?.filter(|item| !item.is_expired()))
+}
+
+/// an exptime as sent by the client turned into a unix time
+fn expires_at(exptime: i64) -> u64 {
+    match exptime {
+        0 => 0,
+        // already expired
+        t if t < 0 => 1,
+        t if t <= MAX_RELATIVE_EXPTIME => unix_now() + t as u64,
+        t => t as u64,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// a command line and the data block of storage commands
+struct Command {
+    args: Vec<String>,
+    data: Option<Vec<u8>>,
+}
+
+/// Decodes command lines with their data blocks and encodes replies.
+struct MemcacheCodec;
+
+impl Decoder for MemcacheCodec {
+    type Item = Command;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Command>> {
+        let end = match src.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None if src.len() > MAX_LINE => return Err(KvError::Protocol("line too long".to_owned())),
+            None => return Ok(None),
+        };
+        let line = std::str::from_utf8(&src[..end])
+            .map_err(|_| KvError::Protocol("command line is not UTF-8".to_owned()))?;
+        let args: Vec<String> = line.split_ascii_whitespace().map(str::to_owned).collect();
+
+        let data_len = match args.first().map(String::as_str) {
+            Some("set") | Some("add") | Some("replace") | Some("cas") => {
+                args.get(4).and_then(|len| len.parse::<usize>().ok())
+                    .filter(|&len| len <= MAX_DATA)
+                    .ok_or_else(|| KvError::Protocol("bad command line format".to_owned()))?
+            },
+            _ => {
+                src.advance(end + 1);
+                return Ok(Some(Command { args, data: None }));
+            },
+        };
+
+        let start = end + 1;
+        if src.len() < start + data_len + 2 {
+            src.reserve(start + data_len + 2 - src.len());
+            return Ok(None);
+        }
+        if &src[start + data_len..start + data_len + 2] != b"\r\n" {
+            return Err(KvError::Protocol("bad data chunk".to_owned()));
+        }
+        let data = src[start..start + data_len].to_vec();
+        src.advance(start + data_len + 2);
+        Ok(Some(Command { args, data: Some(data) }))
+    }
+}
+
+impl Encoder<String> for MemcacheCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, item: String, dst: &mut BytesMut) -> Result<()> {
+        dst.put_slice(item.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::io;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use log::{info, warn};
+
+use crate::{Client, ConnectOptions, KvError, Result};
+
+/// options for `ClientPool::connect`
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    /// connections opened up front and kept open by the health check
+    pub min_connections: usize,
+    /// connections checked out at once, further callers wait
+    pub max_connections: usize,
+    pub connect_timeout: Duration,
+    /// how often idle connections are pinged, failed ones are replaced
+    pub health_check_interval: Duration,
+    /// connections idle for longer are pinged before they're handed out,
+    /// the server may have closed them in the meantime
+    pub ping_idle_after: Duration,
+    /// keyspace of every connection, the default one if `None`
+    pub keyspace: Option<String>,
+    /// codec, TLS and credentials of every connection
+    pub connect: ConnectOptions,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            min_connections: 1,
+            max_connections: 16,
+            connect_timeout: Duration::from_secs(5),
+            health_check_interval: Duration::from_secs(30),
+            ping_idle_after: Duration::from_secs(1),
+            keyspace: None,
+            connect: ConnectOptions::default(),
+        }
+    }
+}
+
+/// A pool of connections to one kvs-server.
+///
+/// Handles are cheap to clone and share the connections. Each call checks a
+/// connection out for its duration, `acquire` hands one out for as long as
+/// needed and gives access to every `Client` method.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addr: String,
+    options: PoolOptions,
+    idle: Mutex<Vec<Idle>>,
+    // one permit per connection that may be checked out
+    permits: Arc<Semaphore>,
+}
+
+/// a connection waiting in the pool
+struct Idle {
+    client: Client,
+    since: Instant,
+}
+
+impl Idle {
+    fn new(client: Client) -> Self {
+        Idle { client, since: Instant::now() }
+    }
+}
+
+impl ClientPool {
+    /// open `min_connections` to `addr` and start the health check
+    pub async fn connect(addr: impl Into<String>, options: PoolOptions) -> Result<Self> {
+        if options.max_connections == 0 || options.min_connections > options.max_connections {
+            return Err(KvError::StringError("pool needs 0 < min_connections <= max_connections".to_owned()));
+        }
+        let inner = Arc::new(PoolInner {
+            addr: addr.into(),
+            permits: Arc::new(Semaphore::new(options.max_connections)),
+            idle: Mutex::new(Vec::with_capacity(options.max_connections)),
+            options,
+        });
+        inner.fill().await?;
+        tokio::spawn(health_check(Arc::downgrade(&inner)));
+        Ok(ClientPool { inner })
+    }
+
+    /// check a connection out, waiting while `max_connections` are in use
+    pub async fn acquire(&self) -> Result<PooledClient> {
+        let permit = self.inner.permits.clone().acquire_owned().await.unwrap();
+        loop {
+            // skip connections that were closed since they were returned
+            let idle = self.inner.idle.lock().unwrap().pop();
+            let Idle { client, since } = match idle {
+                Some(idle) if idle.client.is_closed() => continue,
+                Some(idle) => idle,
+                None => break,
+            };
+            if since.elapsed() < self.inner.options.ping_idle_after || self.inner.ping(&client).await {
+                return Ok(PooledClient { client: Some(client), pool: self.inner.clone(), _permit: permit });
+            }
+        }
+        let client = self.inner.open().await?;
+        Ok(PooledClient { client: Some(client), pool: self.inner.clone(), _permit: permit })
+    }
+
+    // A connection can still be closed by the server between the check in
+    // `acquire` and the request. Gets and sets are safe to repeat, so they
+    // are tried once more on another connection. The first one is given
+    // back before, its permit may be the only one.
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        let client = self.acquire().await?;
+        match client.get(key.clone()).await {
+            Err(_) if client.is_closed() => {
+                drop(client);
+                self.acquire().await?.get(key).await
+            },
+            res => res,
+        }
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        let client = self.acquire().await?;
+        match client.set(key.clone(), value.clone()).await {
+            Err(_) if client.is_closed() => {
+                drop(client);
+                self.acquire().await?.set(key, value).await
+            },
+            res => res,
+        }
+    }
+
+    /// Not retried: the first attempt may have removed the key, a second
+    /// one would report a missing key for a successful remove.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.acquire().await?.remove(key).await
+    }
+
+    /// open connections currently waiting in the pool
+    pub fn idle_connections(&self) -> usize {
+        self.inner.idle.lock().unwrap().iter().filter(|idle| !idle.client.is_closed()).count()
+    }
+}
+
+impl PoolInner {
+    async fn open(&self) -> Result<Client> {
+        let connect = Client::connect_with(self.addr.as_str(), self.options.connect.clone());
+        let mut client = match time::timeout(self.options.connect_timeout, connect).await {
+            Ok(client) => client?,
+            Err(_) => return Err(KvError::Io(io::Error::new(
+                io::ErrorKind::TimedOut, format!("connecting to {} timed out", self.addr)))),
+        };
+        if let Some(name) = &self.options.keyspace {
+            client.use_keyspace(name.clone());
+        }
+        Ok(client)
+    }
+
+    /// whether `client` answers a ping within `connect_timeout`
+    async fn ping(&self, client: &Client) -> bool {
+        matches!(time::timeout(self.options.connect_timeout, client.ping()).await, Ok(Ok(())))
+    }
+
+    /// open connections until `min_connections` are idle or checked out
+    async fn fill(&self) -> Result<()> {
+        let in_use = self.options.max_connections - self.permits.available_permits();
+        while self.idle.lock().unwrap().len() + in_use < self.options.min_connections {
+            let client = self.open().await?;
+            self.idle.lock().unwrap().push(Idle::new(client));
+        }
+        Ok(())
+    }
+}
+
+/// ping the idle connections every `health_check_interval` until the pool is dropped
+async fn health_check(pool: Weak<PoolInner>) {
+    let interval = match pool.upgrade() {
+        Some(pool) => pool.options.health_check_interval,
+        None => return,
+    };
+    loop {
+        time::sleep(interval).await;
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        let clients: Vec<Idle> = pool.idle.lock().unwrap().drain(..).collect();
+        let total = clients.len();
+        let mut healthy = Vec::with_capacity(total);
+        for idle in clients {
+            if pool.ping(&idle.client).await {
+                healthy.push(idle);
+            }
+        }
+        if healthy.len() < total {
+            info!("dropped {} broken connections to {}", total - healthy.len(), pool.addr);
+        }
+        pool.idle.lock().unwrap().extend(healthy);
+
+        if let Err(e) = pool.fill().await {
+            warn!("failed to reconnect to {}: {}", pool.addr, e);
+        }
+    }
+}
+
+/// A connection checked out of a `ClientPool`, returned when dropped
+pub struct PooledClient {
+    client: Option<Client>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            if !client.is_closed() {
+                self.pool.idle.lock().unwrap().push(Idle::new(client));
+            }
+        }
+    }
+}
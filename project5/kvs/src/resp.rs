@@ -0,0 +1,268 @@
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::oneshot::Receiver;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use log::{error, info};
+
+use crate::{KvsEngine, KvError, Result};
+
+// refuse larger frames instead of buffering them
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Speaks RESP2 so redis-cli and Redis client libraries can use the engine.
+///
+/// Only GET, SET, DEL, EXISTS, MGET, PING, INFO and QUIT are understood,
+/// everything else is answered with an error. Keys and values have to be
+/// UTF-8.
+pub struct RespServer<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> RespServer<E> {
+    pub fn new(engine: E) -> Self {
+        RespServer { engine }
+    }
+
+    pub async fn run<A: ToSocketAddrs>(&mut self, addr: A, rx: Receiver<()>) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+
+        tokio::select! {
+            _ = async move {
+                loop {
+                    let (socket, addr) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            error!("accept failed: {}", e);
+                            continue;
+                        },
+                    };
+                    let engine = self.engine.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(engine, socket).await {
+                            error!("RESP connection from {} failed: {}", addr, e);
+                        }
+                    });
+                }
+            } => {}
+            _ = rx => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// answer commands in order, as RESP requires
+async fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let mut framed = Framed::new(stream, RespCodec);
+    while let Some(args) = framed.next().await {
+        let args = match args {
+            Ok(args) => args,
+            Err(KvError::Protocol(msg)) => {
+                // the stream can't be resynchronized after a malformed frame
+                framed.send(RespValue::Error(format!("ERR Protocol error: {}", msg))).await?;
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"quit");
+        let reply = execute(&engine, args).await;
+        framed.send(reply).await?;
+        if quit {
+            info!("RESP client quit");
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+async fn execute<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> RespValue {
+    let mut args = args.into_iter();
+    let name = String::from_utf8_lossy(&args.next().unwrap()).to_lowercase();
+    let args = match args.map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() {
+        Ok(args) => args,
+        Err(_) => return RespValue::Error("ERR keys and values must be valid UTF-8".to_owned()),
+    };
+    let arity_error = || RespValue::Error(format!("ERR wrong number of arguments for '{}' command", name));
+
+    let res = match (name.as_str(), args.len()) {
+        ("ping", 0) => Ok(RespValue::Simple("PONG".to_owned())),
+        ("ping", 1) => Ok(RespValue::bulk(args.into_iter().next().unwrap())),
+        ("quit", 0) => Ok(RespValue::Simple("OK".to_owned())),
+        ("get", 1) => {
+            let key = args.into_iter().next().unwrap();
+            engine.get(key).await.map(|value| value.map_or(RespValue::Nil, RespValue::bulk))
+        },
+        ("set", 2) => {
+            let mut args = args.into_iter();
+            let (key, value) = (args.next().unwrap(), args.next().unwrap());
+            engine.set(key, value).await.map(|_| RespValue::Simple("OK".to_owned()))
+        },
+        // expiry and conditional flags aren't supported
+        ("set", n) if n > 2 => Ok(RespValue::Error("ERR syntax error".to_owned())),
+        ("del", n) if n > 0 => delete(engine, args).await.map(RespValue::Integer),
+        ("exists", n) if n > 0 => {
+            engine.get_many(args).await
+                .map(|values| RespValue::Integer(values.iter().filter(|v| v.is_some()).count() as i64))
+        },
+        ("mget", n) if n > 0 => {
+            engine.get_many(args).await.map(|values| RespValue::Array(
+                values.into_iter().map(|value| value.map_or(RespValue::Nil, RespValue::bulk)).collect()))
+        },
+        ("info", 0) | ("info", 1) => engine.stats().await.map(|stats| {
+            let mut info = format!("# Server\r\nkvs_version:{}\r\n# Engine\r\n", env!("CARGO_PKG_VERSION"));
+            for line in stats.to_string().lines() {
+                info.push_str(&line.replacen(": ", ":", 1));
+                info.push_str("\r\n");
+            }
+            RespValue::bulk(info)
+        }),
+        ("ping", _) | ("quit", _) | ("get", _) | ("set", _) | ("del", _)
+            | ("exists", _) | ("mget", _) | ("info", _) => Ok(arity_error()),
+        _ => Ok(RespValue::Error(format!("ERR unknown command '{}'", name))),
+    };
+    res.unwrap_or_else(|e| RespValue::Error(format!("ERR {}", e)))
+}
+
+/// remove the keys, counting the ones that existed
+async fn delete<E: KvsEngine>(engine: &E, keys: Vec<String>) -> Result<i64> {
+    let mut removed = 0;
+    for key in keys {
+        match engine.remove(key).await {
+            Ok(()) => removed += 1,
+            Err(KvError::KeyNotFound) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(removed)
+}
+
+/// a RESP2 reply
+#[derive(Debug, Clone, PartialEq)]
+enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    // the null bulk string
+    Nil,
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn bulk(value: String) -> Self {
+        RespValue::Bulk(value.into_bytes())
+    }
+}
+
+/// Decodes commands, sent either as arrays of bulk strings or inline as
+/// space separated words, and encodes replies.
+struct RespCodec;
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let parsed = if src.first() == Some(&b'*') {
+            parse_array(src)?
+        } else {
+            parse_inline(src)?
+        };
+        Ok(parsed.map(|(args, len)| {
+            src.advance(len);
+            args
+        }))
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, item: RespValue, dst: &mut BytesMut) -> Result<()> {
+        match item {
+            RespValue::Simple(s) => dst.put_slice(format!("+{}\r\n", s).as_bytes()),
+            RespValue::Error(e) => dst.put_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes()),
+            RespValue::Integer(n) => dst.put_slice(format!(":{}\r\n", n).as_bytes()),
+            RespValue::Bulk(bytes) => {
+                dst.put_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                dst.put_slice(&bytes);
+                dst.put_slice(b"\r\n");
+            },
+            RespValue::Nil => dst.put_slice(b"$-1\r\n"),
+            RespValue::Array(values) => {
+                dst.put_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    self.encode(value, dst)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// the line starting at `pos` without its CRLF, and where the next one starts
+fn read_line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let end = buf[pos..].windows(2).position(|w| w == b"\r\n")?;
+    Some((&buf[pos..pos + end], pos + end + 2))
+}
+
+/// the number following a type byte such as `*3` or `$5`
+fn read_len(buf: &[u8], pos: usize, kind: u8, max: usize) -> Result<Option<(usize, usize)>> {
+    let (line, next) = match read_line(buf, pos) {
+        Some(line) => line,
+        None if buf.len() - pos > 32 => return Err(KvError::Protocol("length line too long".to_owned())),
+        None => return Ok(None),
+    };
+    if line.first() != Some(&kind) {
+        return Err(KvError::Protocol(format!("expected '{}'", kind as char)));
+    }
+    let len = std::str::from_utf8(&line[1..]).ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|&n| n <= max)
+        .ok_or_else(|| KvError::Protocol("invalid length".to_owned()))?;
+    Ok(Some((len, next)))
+}
+
+/// an array of bulk strings and its length in bytes, `None` if incomplete
+fn parse_array(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let (count, mut pos) = match read_len(buf, 0, b'*', MAX_ARGS)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let (len, start) = match read_len(buf, pos, b'$', MAX_BULK_LEN)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if buf.len() < start + len + 2 {
+            return Ok(None);
+        }
+        if &buf[start + len..start + len + 2] != b"\r\n" {
+            return Err(KvError::Protocol("bulk string not terminated by CRLF".to_owned()));
+        }
+        args.push(buf[start..start + len].to_vec());
+        pos = start + len + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// a command typed by hand, e.g. over telnet
+fn parse_inline(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() > MAX_BULK_LEN => return Err(KvError::Protocol("inline command too long".to_owned())),
+        None => return Ok(None),
+    };
+    let args = buf[..end]
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_vec())
+        .collect();
+    Ok(Some((args, end + 1)))
+}
@@ -2,19 +2,50 @@ extern crate tokio;
 extern crate futures;
 
 use tokio::sync::oneshot::Receiver;
-use futures::{TryStreamExt, SinkExt};
+use futures::{StreamExt, TryStreamExt, SinkExt};
 use tokio::{spawn};
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::net::{TcpListener, ToSocketAddrs, UnixListener};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
 use tokio_serde::{SymmetricallyFramed};
 use tokio_serde::formats::SymmetricalBincode;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
-use crate::{KvsEngine, Result, Request, Response, SymmetricalReader, SymmetricalWriter};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::{KvsEngine, KvError, Result, Request, Response, Tagged, SymmetricalReader, SymmetricalWriter};
+use crate::{Acl, Authenticator, Codec, Hello, HelloReply, ServerTls, WireFormat, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use crate::client::{split_stream, ConnReader, ConnWriter};
+use crate::engines::check_client_name;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+use log::{error, info};
+
+// requests of one connection handled at the same time
+const MAX_IN_FLIGHT: usize = 256;
+// failed logins before the connection is closed
+const MAX_AUTH_FAILURES: u32 = 3;
 
+#[derive(Clone)]
 pub struct Server<E: KvsEngine> {
     engine: E,
     is_stop: Arc<AtomicBool>,
+    // connections without a request for this long are closed
+    idle_timeout: Option<Duration>,
+    // for clients that leave the choice to the server
+    codec: Codec,
+    // plain TCP if `None`
+    tls: Option<TlsAcceptor>,
+    // anyone may send requests if `None`
+    auth: Option<Arc<Authenticator>>,
+    // everything is allowed if `None`
+    acl: Option<Arc<Acl>>,
 }
 
 impl<E: KvsEngine> Server<E> {
@@ -22,9 +53,44 @@ impl<E: KvsEngine> Server<E> {
         Ok(Server {
             engine,
             is_stop,
+            idle_timeout: None,
+            codec: Codec::default(),
+            tls: None,
+            auth: None,
+            acl: None,
         })
     }
 
+    /// close connections that send no request for `timeout`, watches are exempt
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// serialize requests and responses with `codec` unless a client asks for another one
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// accept TLS connections only, fails if the certificate or key can't be loaded
+    pub fn with_tls(mut self, tls: &ServerTls) -> Result<Self> {
+        self.tls = Some(tls.acceptor()?);
+        Ok(self)
+    }
+
+    /// refuse requests until the client logged in with an `Auth` request
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// check every request against `acl`, which may be reloaded while running
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
     pub async fn run<A: ToSocketAddrs>(&mut self, addr: A, rx: Receiver<()>) -> Result<()> {
         let listener = TcpListener::bind(addr).await?;
 
@@ -32,11 +98,7 @@ impl<E: KvsEngine> Server<E> {
             _ = async move {
                 loop {
                     let (socket, addr) = listener.accept().await.unwrap();
-                
-                    let engine = self.engine.clone();
-                    tokio::spawn(async move {
-                        handle_connection(engine, socket).await.unwrap();
-                    });
+                    self.spawn_connection(socket, addr.to_string(), self.tls.clone());
                 }
             } => {}
             _ = rx => {
@@ -46,38 +108,424 @@ impl<E: KvsEngine> Server<E> {
 
         Ok(())
     }
+
+    /// Serve the same protocol on a Unix socket at `path`, readable and
+    /// writable as `mode` allows. A socket left behind by an earlier run is
+    /// replaced, the socket is removed again when `rx` fires.
+    pub async fn run_unix<P: AsRef<Path>>(&mut self, path: P, mode: u32, rx: Receiver<()>) -> Result<()> {
+        let path = path.as_ref();
+        let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+        if stale {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(KvError::Io(io::Error::new(
+                    io::ErrorKind::AddrInUse, format!("{} is in use by a running server", path.display()))));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+
+        tokio::select! {
+            _ = async {
+                loop {
+                    let socket = match listener.accept().await {
+                        Ok((socket, _)) => socket,
+                        Err(e) => {
+                            error!("accept failed: {}", e);
+                            continue;
+                        },
+                    };
+                    // access is guarded by the file permissions, not TLS
+                    self.spawn_connection(socket, path.display().to_string(), None);
+                }
+            } => {}
+            _ = rx => {}
+        }
+
+        let _ = fs::remove_file(path);
+        Ok(())
+    }
+
+    /// handle an accepted connection in the background
+    fn spawn_connection<S>(&self, socket: S, peer: String, tls: Option<TlsAcceptor>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        let idle_timeout = self.idle_timeout;
+        let codec = self.codec;
+        let access = Access { auth: self.auth.clone(), acl: self.acl.clone() };
+        tokio::spawn(async move {
+            let res = match tls {
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(stream) => handle_connection(engine, stream, idle_timeout, codec, access).await,
+                    Err(e) => Err(KvError::Tls(e.to_string())),
+                },
+                None => handle_connection(engine, socket, idle_timeout, codec, access).await,
+            };
+            if let Err(e) = res {
+                error!("connection from {} failed: {}", peer, e);
+            }
+        });
+    }
 }
 
-async fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let (read_half, write_half) = stream.into_split();
-    let mut reader: SymmetricalReader<Request> = SymmetricallyFramed::new(FramedRead::new(read_half, LengthDelimitedCodec::new()), SymmetricalBincode::default());
-    let mut writer: SymmetricalWriter<Response> = SymmetricallyFramed::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()), SymmetricalBincode::default());
+/// who may connect and what they may do
+#[derive(Clone)]
+struct Access {
+    auth: Option<Arc<Authenticator>>,
+    acl: Option<Arc<Acl>>,
+}
 
-    if let Some(req) = reader.try_next().await? {
-        match req {
-            Request::Get{key} => {
-                let resp = match engine.get(key).await {
-                    Ok(value) => Response::Get(value),
-                    Err(e) => Response::Err(e.to_string()),
+/// Handle requests until the client closes the connection. Requests run
+/// concurrently, each response is tagged with the id of its request and
+/// sent as soon as it's ready. With an authenticator, everything but `Auth`
+/// is refused until the client logged in, with an ACL every request is
+/// checked before it reaches the engine.
+async fn handle_connection<E, S>(
+    engine: E,
+    stream: S,
+    idle_timeout: Option<Duration>,
+    codec: Codec,
+    access: Access,
+) -> Result<()>
+where
+    E: KvsEngine,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read_half, write_half) = split_stream(stream);
+    let mut frames_in = FramedRead::new(read_half, LengthDelimitedCodec::new());
+    let mut frames_out = FramedWrite::new(write_half, LengthDelimitedCodec::new());
+    let codec = match handshake(&engine, &mut frames_in, &mut frames_out, idle_timeout, codec).await? {
+        Some(codec) => codec,
+        None => return Ok(()),
+    };
+    let mut reader: SymmetricalReader<Tagged<Request>> = SymmetricallyFramed::new(frames_in, WireFormat::new(codec));
+    let mut writer: SymmetricalWriter<Tagged<Response>> = SymmetricallyFramed::new(frames_out, WireFormat::new(codec));
+
+    // responses of all requests go through one writer
+    let (tx, mut rx) = mpsc::channel::<Tagged<Response>>(MAX_IN_FLIGHT);
+    let write_task = tokio::spawn(async move {
+        while let Some(resp) = rx.recv().await {
+            writer.send(resp).await?;
+        }
+        Ok::<_, KvError>(())
+    });
+
+    // a permit per running request
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut watches = JoinSet::new();
+    let mut authenticated = access.auth.is_none();
+    // who logged in, `None` before that or with the shared secret
+    let mut user: Option<String> = None;
+    let mut auth_failures = 0;
+    loop {
+        // a watching client may have nothing more to say
+        let timeout = if watches.is_empty() { idle_timeout } else { None };
+        let Tagged { id, msg } = match next_request(&mut reader, timeout).await? {
+            Some(req) => req,
+            None => break,
+        };
+        if authenticated {
+            if let Some(Err(e)) = access.acl.as_ref().map(|acl| acl.check(user.as_deref(), &msg)) {
+                let _ = tx.send(Tagged { id, msg: Response::Err(e.to_string()) }).await;
+                continue;
+            }
+        }
+        let engine = engine.clone();
+        let tx = tx.clone();
+        match msg {
+            // answered before the next request is read, so that those see the outcome
+            Request::Auth { user: name, password } => {
+                let resp = match authenticate(access.auth.clone(), name, password).await {
+                    Ok(name) => {
+                        authenticated = true;
+                        user = name;
+                        Response::Auth
+                    },
+                    Err(e) => {
+                        auth_failures += 1;
+                        Response::Err(e.to_string())
+                    },
                 };
-                writer.send(resp).await?;
+                let _ = tx.send(Tagged { id, msg: resp }).await;
+                if auth_failures >= MAX_AUTH_FAILURES {
+                    info!("closing connection after {} failed logins", auth_failures);
+                    break;
+                }
+            },
+            _ if !authenticated => {
+                let _ = tx.send(Tagged { id, msg: Response::Err(KvError::Unauthenticated.to_string()) }).await;
             },
-            Request::Set { key, value } => {
-                // println!("[SetRequest] {key}: {value}");
-                let resp = match engine.set(key, value).await {
+            Request::Watch { keyspace, prefix } => {
+                watches.spawn(watch(engine, keyspace, prefix, id, tx));
+            },
+            req => {
+                let permit = in_flight.clone().acquire_owned().await.unwrap();
+                tokio::spawn(async move {
+                    let resp = dispatch(&engine, req).await;
+                    // only fails if the connection is gone
+                    let _ = tx.send(Tagged { id, msg: resp }).await;
+                    drop(permit);
+                });
+            },
+        }
+    }
+
+    // answer what's in flight, watches end with the connection
+    watches.abort_all();
+    while watches.join_next().await.is_some() {}
+    let _ = in_flight.acquire_many(MAX_IN_FLIGHT as u32).await;
+    drop(tx);
+    write_task.await.unwrap()
+}
+
+/// check a login, returns the user it's for, `None` for the shared secret
+/// or if the server has no authenticator
+async fn authenticate(auth: Option<Arc<Authenticator>>, user: Option<String>, password: String) -> Result<Option<String>> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Ok(None),
+    };
+    // argon2 takes a while, keep it off the runtime's threads
+    let name = user.clone();
+    let res = tokio::task::spawn_blocking(move || auth.verify(user.as_deref(), &password)).await.unwrap();
+    if res.is_err() {
+        info!("failed login for {}", name.as_deref().unwrap_or("<shared secret>"));
+    }
+    res
+}
+
+/// Answer the client's hello with the version and capabilities both sides
+/// support. Returns the codec of the requests to follow, `None` if the
+/// client was turned away.
+async fn handshake<E: KvsEngine>(
+    engine: &E,
+    frames_in: &mut FramedRead<ConnReader, LengthDelimitedCodec>,
+    frames_out: &mut FramedWrite<ConnWriter, LengthDelimitedCodec>,
+    idle_timeout: Option<Duration>,
+    default_codec: Codec,
+) -> Result<Option<Codec>> {
+    let mut reader = SymmetricallyFramed::new(frames_in, SymmetricalBincode::<Hello>::default());
+    let mut writer = SymmetricallyFramed::new(frames_out, SymmetricalBincode::<HelloReply>::default());
+    let hello = match idle_timeout {
+        Some(timeout) => match time::timeout(timeout, reader.try_next()).await {
+            Ok(hello) => hello,
+            Err(_) => return Ok(None),
+        },
+        None => reader.try_next().await,
+    };
+    let hello = match hello {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return Ok(None),
+        // garbage in place of a hello, most likely a client from before the handshake
+        Err(_) => Hello::new(0, Default::default(), None),
+    };
+
+    let reply = if !hello.is_valid() {
+        HelloReply::Rejected("expected a hello, is the client older than the server?".to_owned())
+    } else if hello.version < MIN_PROTOCOL_VERSION {
+        HelloReply::Rejected(format!(
+            "protocol version {} is not supported, the server speaks {} to {}",
+            hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION))
+    } else {
+        HelloReply::Accepted {
+            version: hello.version.min(PROTOCOL_VERSION),
+            capabilities: hello.capabilities & engine.capabilities(),
+            codec: hello.codec.unwrap_or(default_codec),
+        }
+    };
+    let codec = match reply {
+        HelloReply::Accepted { codec, .. } => Some(codec),
+        HelloReply::Rejected(_) => {
+            info!("rejected client: {:?}", reply);
+            None
+        },
+    };
+    writer.send(reply).await?;
+    Ok(codec)
+}
+
+/// answer a request that has a single response
+async fn dispatch<E: KvsEngine>(engine: &E, req: Request) -> Response {
+    match req {
+        Request::Get { keyspace, key } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.get(key).await {
+                    Ok(value) => Response::Get(value),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Set { keyspace, key, value } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.set(key, value).await {
                     Ok(_) => Response::Set,
                     Err(e) => Response::Err(e.to_string()),
-                };
-                writer.send(resp).await?;
-            },
-            Request::Remove { key } => {
-                let resp = match engine.remove(key).await {
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Remove { keyspace, key } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.remove(key).await {
                     Ok(_) => Response::Remove,
                     Err(e) => Response::Err(e.to_string()),
-                };
-                writer.send(resp).await?;
-            },
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Stats { keyspace } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.stats().await {
+                    Ok(stats) => Response::Stats(stats),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::CreateKeyspace { name } => {
+            let res = match check_client_name(&name) {
+                Ok(()) => engine.create_keyspace(name).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(_) => Response::CreateKeyspace,
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::DropKeyspace { name } => {
+            let res = match check_client_name(&name) {
+                Ok(()) => engine.drop_keyspace(name).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(_) => Response::DropKeyspace,
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Batch { keyspace, ops } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.batch(ops).await {
+                    Ok(_) => Response::Batch,
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Cas { keyspace, key, expected, new } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.compare_and_swap(key, expected, new).await {
+                    Ok(swapped) => Response::Cas(swapped),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Incr { keyspace, key, delta } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.incr(key, delta).await {
+                    Ok(value) => Response::Incr(value),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Append { keyspace, key, suffix } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.append(key, suffix).await {
+                    Ok(len) => Response::Append(len),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::MGet { keyspace, keys } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.get_many(keys).await {
+                    Ok(values) => Response::MGet(values),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::MSet { keyspace, pairs } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.set_many(pairs).await {
+                    Ok(_) => Response::MSet,
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Scan { keyspace, start, end, limit } => {
+            match scoped(engine, keyspace) {
+                Ok(engine) => match engine.scan(start, end, limit).await {
+                    Ok(pairs) => Response::Scan(pairs),
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::ListKeyspaces => {
+            match engine.list_keyspaces().await {
+                Ok(names) => Response::ListKeyspaces(names.into_iter().filter(|name| check_client_name(name).is_ok()).collect()),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        },
+        Request::Ping => Response::Pong,
+        Request::Auth { .. } => Response::Err("auth is answered by the connection".to_owned()),
+        Request::Watch { .. } => Response::Err("watch has no single response".to_owned()),
+    }
+}
+
+/// push the changes under `prefix` until the event stream ends
+async fn watch<E: KvsEngine>(engine: E, keyspace: Option<String>, prefix: String, id: u64, tx: mpsc::Sender<Tagged<Response>>) {
+    let mut events = match scoped(&engine, keyspace) {
+        Ok(engine) => engine.watch(prefix),
+        Err(e) => {
+            let _ = tx.send(Tagged { id, msg: Response::Err(e.to_string()) }).await;
+            return;
+        },
+    };
+    if tx.send(Tagged { id, msg: Response::Watch }).await.is_err() {
+        return;
+    }
+    while let Some(event) = events.next().await {
+        let msg = match event {
+            Ok(event) => Response::Event(event),
+            Err(e) => Response::Err(e.to_string()),
+        };
+        if tx.send(Tagged { id, msg }).await.is_err() {
+            return;
         }
     }
-    Ok(())
+}
+
+/// the next request, `None` once the client closed the connection or was idle too long
+async fn next_request(reader: &mut SymmetricalReader<Tagged<Request>>, idle_timeout: Option<Duration>) -> Result<Option<Tagged<Request>>> {
+    let req = match idle_timeout {
+        Some(timeout) => match time::timeout(timeout, reader.try_next()).await {
+            Ok(req) => req,
+            Err(_) => {
+                info!("closing connection idle for {:?}", timeout);
+                return Ok(None);
+            },
+        },
+        None => reader.try_next().await,
+    };
+    match req {
+        // TLS clients often hang up without a close_notify
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        req => Ok(req?),
+    }
+}
+
+/// the engine handle for the keyspace named in a request
+fn scoped<E: KvsEngine>(engine: &E, keyspace: Option<String>) -> Result<E> {
+    match keyspace {
+        Some(name) => check_client_name(&name).and_then(|_| engine.keyspace(&name)),
+        None => Ok(engine.clone()),
+    }
 }
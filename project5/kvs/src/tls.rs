@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::{KvError, Result};
+
+/// TLS settings of a listener, files are PEM encoded
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// if set, clients must present a certificate signed by one of these CAs
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl ServerTls {
+    pub(crate) fn acceptor(&self) -> Result<TlsAcceptor> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = match &self.client_ca_file {
+            Some(path) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(path)?), provider())
+                    .build()
+                    .map_err(|e| KvError::Tls(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)
+            .map_err(tls_error)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// TLS settings of a client, files are PEM encoded
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    /// CAs the server's certificate is checked against
+    pub ca_file: PathBuf,
+    /// the name the server's certificate has to be issued for
+    pub server_name: String,
+    /// certificate and key presented to servers that verify clients
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+impl ClientTls {
+    pub(crate) fn connector(&self) -> Result<(TlsConnector, ServerName<'static>)> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(load_roots(&self.ca_file)?);
+        let config = match (&self.cert_file, &self.key_file) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(tls_error)?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(KvError::Tls("a client certificate needs both a cert and a key file".to_owned())),
+        };
+        let name = ServerName::try_from(self.server_name.clone())
+            .map_err(|_| KvError::Tls(format!("invalid server name: {}", self.server_name)))?;
+        Ok((TlsConnector::from(Arc::new(config)), name))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn tls_error(e: rustls::Error) -> KvError {
+    KvError::Tls(e.to_string())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvError::Tls(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| KvError::Tls(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
@@ -0,0 +1,171 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{hash_password, Acl, Authenticator, BatchOp, Client, Credentials, Result, SledEngine};
+use predicates::str::contains;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// users `alice`, `bob` and `root` with their names as passwords, and a shared secret
+fn write_auth_file(dir: &Path) -> PathBuf {
+    let user = |name: &str| json!({ "password_hash": hash_password(name).unwrap() });
+    let config = json!({
+        "shared_secret_hash": hash_password("team-secret").unwrap(),
+        "users": { "alice": user("alice"), "bob": user("bob"), "root": user("root") },
+    });
+    let path = dir.join("auth.json");
+    fs::write(&path, config.to_string()).unwrap();
+    path
+}
+
+fn write_acl_file(path: &Path, bob_ops: &[&str]) {
+    let rules = json!({
+        "users": {
+            // admin on a prefix doesn't make alice an administrator
+            "alice": [
+                { "prefix": "team-a/", "ops": ["read", "write", "admin"] },
+                { "keyspace": "archive", "prefix": "team-a/", "ops": ["read"] },
+            ],
+            "bob": [{ "prefix": "team-b/", "ops": bob_ops }],
+            "root": [
+                { "prefix": "", "ops": ["read", "write", "admin"] },
+                { "keyspace": "archive", "prefix": "", "ops": ["read", "write", "admin"] },
+            ],
+        },
+        "default": [{ "prefix": "public/", "ops": ["read"] }],
+    });
+    fs::write(path, rules.to_string()).unwrap();
+}
+
+async fn login(addr: &str, user: Option<&str>, password: &str) -> Result<Client> {
+    let credentials = Credentials { user: user.map(str::to_owned), password: password.to_owned() };
+    Client::connect_with_credentials(addr, credentials).await
+}
+
+fn assert_denied<T: std::fmt::Debug>(res: Result<T>) {
+    let err = res.unwrap_err();
+    assert!(err.to_string().contains("permission denied"), "{}", err);
+}
+
+#[tokio::test]
+async fn acl_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth = Authenticator::from_file(write_auth_file(temp_dir.path()))?;
+    let acl_path = temp_dir.path().join("acl.json");
+    write_acl_file(&acl_path, &["read"]);
+    let acl = Arc::new(Acl::from_file(&acl_path)?);
+
+    let store = SledEngine::<RayonThreadPool>::open(temp_dir.path().join("data"), 2)?;
+    let addr = "127.0.0.1:4125";
+    let _stop = common::serve(common::new_server(store).with_auth(auth).with_acl(acl.clone()), addr).await;
+
+    let mut root = login(addr, Some("root"), "root").await?;
+    root.create_keyspace("archive".to_owned()).await?;
+    assert_denied(root.create_keyspace("other".to_owned()).await);
+    root.stats().await?;
+    for key in ["team-a/1", "team-b/1", "public/1"] {
+        root.set(key.to_owned(), "value".to_owned()).await?;
+    }
+    root.use_keyspace("archive");
+    for key in ["team-a/1", "team-b/1"] {
+        root.set(key.to_owned(), "archived".to_owned()).await?;
+    }
+
+    let alice = login(addr, Some("alice"), "alice").await?;
+    alice.set("team-a/2".to_owned(), "value".to_owned()).await?;
+    assert_eq!(alice.get("team-a/1".to_owned()).await?, Some("value".to_owned()));
+    assert_denied(alice.get("team-b/1".to_owned()).await);
+    assert_denied(alice.set("team-b/1".to_owned(), "mine".to_owned()).await);
+    assert_denied(alice.get("public/1".to_owned()).await);
+    // requests with several keys are refused as a whole
+    assert_denied(alice.get_many(vec!["team-a/1".to_owned(), "team-b/1".to_owned()]).await);
+    assert_denied(alice.batch(vec![
+        BatchOp::Set { key: "team-a/3".to_owned(), value: "value".to_owned() },
+        BatchOp::Remove { key: "team-b/1".to_owned() },
+    ]).await);
+    assert_eq!(alice.get("team-a/3".to_owned()).await?, None);
+    // scans have to stay inside an allowed prefix
+    let pairs = alice.scan("team-a/".to_owned(), Some("team-a0".to_owned()), None).await?;
+    assert_eq!(pairs.len(), 2);
+    assert_denied(alice.scan("team-a/".to_owned(), None, None).await);
+    assert_denied(alice.scan("team-a/".to_owned(), Some("team-b/".to_owned()), None).await);
+    assert_denied(alice.create_keyspace("mine".to_owned()).await);
+    assert_denied(alice.stats().await);
+    assert_denied(alice.list_keyspaces().await);
+    alice.ping().await?;
+
+    // rules only apply to their own keyspace
+    let mut alice = login(addr, Some("alice"), "alice").await?;
+    alice.use_keyspace("archive");
+    assert_eq!(alice.get("team-a/1".to_owned()).await?, Some("archived".to_owned()));
+    assert_denied(alice.set("team-a/1".to_owned(), "mine".to_owned()).await);
+    let mut bob = login(addr, Some("bob"), "bob").await?;
+    assert_eq!(bob.get("team-b/1".to_owned()).await?, Some("value".to_owned()));
+    bob.use_keyspace("archive");
+    assert_denied(bob.get("team-b/1".to_owned()).await);
+
+    // the shared secret and unlisted users get the default rules
+    let guest = login(addr, None, "team-secret").await?;
+    assert_eq!(guest.get("public/1".to_owned()).await?, Some("value".to_owned()));
+    assert_denied(guest.set("public/1".to_owned(), "value".to_owned()).await);
+    assert_denied(guest.get("team-a/1".to_owned()).await);
+
+    // reloads apply to open connections, broken files are ignored
+    let bob = login(addr, Some("bob"), "bob").await?;
+    assert_denied(bob.set("team-b/1".to_owned(), "bob's".to_owned()).await);
+    write_acl_file(&acl_path, &["read", "write"]);
+    acl.reload()?;
+    bob.set("team-b/1".to_owned(), "bob's".to_owned()).await?;
+    fs::write(&acl_path, "{ not json")?;
+    assert!(acl.reload().is_err());
+    bob.set("team-b/2".to_owned(), "bob's".to_owned()).await?;
+
+    Ok(())
+}
+
+#[test]
+fn acl_reload_on_sighup() {
+    let temp_dir = TempDir::new().unwrap();
+    let auth_file = write_auth_file(temp_dir.path());
+    let acl_file = temp_dir.path().join("acl.json");
+    write_acl_file(&acl_file, &["read"]);
+    let password_file = temp_dir.path().join("password");
+    fs::write(&password_file, "bob\n").unwrap();
+    let addr = "127.0.0.1:4126";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .arg("--auth-file").arg(&auth_file)
+        .arg("--acl-file").arg(&acl_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr, "--user", "bob"])
+            .arg("--password-file").arg(&password_file)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "team-b/1", "value"]).assert().failure().stderr(contains("permission denied"));
+
+    write_acl_file(&acl_file, &["read", "write"]);
+    Command::new("kill").args(["-HUP", &server.id().to_string()]).assert().success();
+    std::thread::sleep(Duration::from_millis(500));
+
+    client(&["set", "team-b/1", "value"]).assert().success();
+    client(&["get", "team-b/1"]).assert().success().stdout("value\n");
+    client(&["set", "team-a/1", "value"]).assert().failure().stderr(contains("permission denied"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
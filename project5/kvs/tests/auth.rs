@@ -0,0 +1,128 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use kvs::{hash_password, Authenticator, Client, Credentials, MemEngine, Request, Response, Result};
+use predicates::str::contains;
+use serde_json::json;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// an auth file with the shared secret `team-secret` and the user `alice`
+fn write_auth_file(dir: &Path) -> PathBuf {
+    let config = json!({
+        "shared_secret_hash": hash_password("team-secret").unwrap(),
+        "users": { "alice": { "password_hash": hash_password("alice-password").unwrap() } },
+    });
+    let path = dir.join("auth.json");
+    fs::write(&path, config.to_string()).unwrap();
+    path
+}
+
+fn credentials(user: Option<&str>, password: &str) -> Credentials {
+    Credentials { user: user.map(str::to_owned), password: password.to_owned() }
+}
+
+#[tokio::test]
+async fn auth_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth = Authenticator::from_file(write_auth_file(temp_dir.path()))?;
+    let addr = "127.0.0.1:4123";
+    let _stop = common::serve(common::new_server(MemEngine::new()).with_auth(auth), addr).await;
+
+    // nothing but a login before the login
+    let client = Client::connect(addr).await?;
+    let err = client.set("key".to_owned(), "value".to_owned()).await.unwrap_err();
+    assert!(err.to_string().contains("authentication required"), "{}", err);
+    assert!(client.ping().await.is_err());
+    let err = client.authenticate(&credentials(Some("alice"), "wrong")).await.unwrap_err();
+    assert!(err.to_string().contains("authentication failed"), "{}", err);
+    // a user's own password, not the shared secret
+    assert!(client.authenticate(&credentials(Some("alice"), "team-secret")).await.is_err());
+    client.authenticate(&credentials(Some("alice"), "alice-password")).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+
+    let client = Client::connect_with_credentials(addr, credentials(None, "team-secret")).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+    // users without their own password use the shared secret
+    let client = Client::connect_with_credentials(addr, credentials(Some("bob"), "team-secret")).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+    assert!(Client::connect_with_credentials(addr, credentials(None, "alice-password")).await.is_err());
+
+    // too many failed logins close the connection
+    let client = Client::connect(addr).await?;
+    for _ in 0..3 {
+        let req = Request::Auth { user: None, password: "guess".to_owned() };
+        assert!(matches!(client.send_request(req).await?, Some(Response::Err(_))));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(client.is_closed());
+
+    Ok(())
+}
+
+#[test]
+fn auth_cli() {
+    let temp_dir = TempDir::new().unwrap();
+    let auth_file = write_auth_file(temp_dir.path());
+    let password_file = temp_dir.path().join("password");
+    fs::write(&password_file, "alice-password\n").unwrap();
+    let addr = "127.0.0.1:4124";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .arg("--auth-file").arg(&auth_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr, "--user", "alice"])
+            .arg("--password-file").arg(&password_file)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// the RESP, HTTP and memcached listeners can't log clients in, so they don't start
+#[test]
+fn auth_refuses_open_listeners() {
+    let temp_dir = TempDir::new().unwrap();
+    let auth_file = write_auth_file(temp_dir.path());
+    let side_addr = "127.0.0.1:4132";
+    for listener in ["--resp-addr", "--http-addr", "--memcache-addr"] {
+        for restriction in ["--auth-file", "--acl-file"] {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--engine", "memory", "--addr", "127.0.0.1:4133", listener, side_addr])
+                .arg(restriction).arg(&auth_file)
+                .current_dir(&temp_dir)
+                .assert()
+                .failure()
+                .stderr(contains("cannot be used with"));
+        }
+    }
+
+    // so an unauthenticated SET over RESP has nowhere to go
+    let set = std::net::TcpStream::connect(side_addr)
+        .and_then(|mut stream| stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n"));
+    assert!(set.is_err());
+}
@@ -0,0 +1,73 @@
+//! Fixtures shared by the integration tests, not every test uses all of them.
+#![allow(dead_code)]
+
+use kvs::{HttpServer, KvsEngine, MemcacheServer, RespServer, Result, Server};
+use std::future::Future;
+use std::sync::{Arc, atomic::AtomicBool};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+/// run a listener in the background until the returned sender is used or dropped
+pub async fn spawn_listener<F, Fut>(run: F) -> oneshot::Sender<()>
+where
+    F: FnOnce(oneshot::Receiver<()>) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let listener = run(rx);
+    tokio::spawn(async move {
+        listener.await.unwrap();
+    });
+    // give it time to bind
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    tx
+}
+
+/// a server without TLS, auth or idle timeout, for the builder methods
+pub fn new_server<E: KvsEngine>(engine: E) -> Server<E> {
+    Server::new(engine, Arc::new(AtomicBool::new(false))).unwrap()
+}
+
+/// run `server` on `addr` until the returned sender is used or dropped
+pub async fn serve<E: KvsEngine>(mut server: Server<E>, addr: &str) -> oneshot::Sender<()> {
+    let addr = addr.to_owned();
+    spawn_listener(move |rx| async move { server.run(addr, rx).await }).await
+}
+
+/// run a plain server for `engine` on `addr`
+pub async fn start_server<E: KvsEngine>(engine: E, addr: &str) -> oneshot::Sender<()> {
+    serve(new_server(engine), addr).await
+}
+
+/// send raw bytes and check the exact reply
+pub async fn expect(stream: &mut TcpStream, request: &str, reply: &str) {
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut buf = vec![0; reply.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), reply, "reply to {:?}", request);
+}
+
+/// run a RESP listener for `engine` on `addr`
+pub async fn start_resp<E: KvsEngine>(engine: E, addr: &str) -> oneshot::Sender<()> {
+    let addr = addr.to_owned();
+    spawn_listener(move |rx| async move { RespServer::new(engine).run(addr, rx).await }).await
+}
+
+/// run an HTTP gateway on `addr` until the returned sender is used or dropped
+pub async fn serve_http<E: KvsEngine>(mut server: HttpServer<E>, addr: &str) -> oneshot::Sender<()> {
+    let addr = addr.to_owned();
+    spawn_listener(move |rx| async move { server.run(addr, rx).await }).await
+}
+
+/// run an HTTP gateway for `engine` on `addr`
+pub async fn start_http<E: KvsEngine>(engine: E, addr: &str) -> oneshot::Sender<()> {
+    serve_http(HttpServer::new(engine), addr).await
+}
+
+/// run a memcached listener for `engine` on `addr`
+pub async fn start_memcache<E: KvsEngine>(engine: E, addr: &str) -> oneshot::Sender<()> {
+    let addr = addr.to_owned();
+    spawn_listener(move |rx| async move { MemcacheServer::new(engine).run(addr, rx).await }).await
+}
@@ -0,0 +1,75 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvOptions, KvStore, KvsEngine, MemEngine, SledEngine, KvError, Result};
+use futures::future;
+use tempfile::TempDir;
+
+// concurrent increments are never lost
+async fn check_counters<E: KvsEngine>(engine: E) -> Result<()> {
+    let tasks: Vec<_> = (0..200)
+        .map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.incr("hits".to_owned(), 1).await })
+        })
+        .collect();
+    for res in future::join_all(tasks).await {
+        res.unwrap()?;
+    }
+    assert_eq!(engine.get("hits".to_owned()).await?, Some("200".to_owned()));
+    assert_eq!(engine.incr("hits".to_owned(), -250).await?, -50);
+    assert_eq!(engine.incr("fresh".to_owned(), 5).await?, 5);
+
+    engine.set("name".to_owned(), "kvs".to_owned()).await?;
+    assert!(matches!(engine.incr("name".to_owned(), 1).await, Err(KvError::NotAnInteger)));
+    engine.set("max".to_owned(), i64::MAX.to_string()).await?;
+    assert!(matches!(engine.incr("max".to_owned(), 1).await, Err(KvError::Overflow)));
+    // failed increments leave the value alone
+    assert_eq!(engine.get("name".to_owned()).await?, Some("kvs".to_owned()));
+
+    let tasks: Vec<_> = (0..100)
+        .map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.append("log".to_owned(), "ab".to_owned()).await })
+        })
+        .collect();
+    for res in future::join_all(tasks).await {
+        res.unwrap()?;
+    }
+    assert_eq!(engine.get("log".to_owned()).await?, Some("ab".repeat(100)));
+    assert_eq!(engine.append("name".to_owned(), "-server".to_owned()).await?, 10);
+    assert_eq!(engine.get("name".to_owned()).await?, Some("kvs-server".to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kvs_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    check_counters(store.clone()).await?;
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    assert_eq!(store.incr("hits".to_owned(), 1).await?, -49);
+    Ok(())
+}
+
+#[tokio::test]
+async fn kvs_sharded_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvOptions { shards: 4, ..KvOptions::default() };
+    check_counters(KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 4, options)?).await
+}
+
+#[tokio::test]
+async fn sled_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_counters(SledEngine::<RayonThreadPool>::open(temp_dir.path(), 4)?).await
+}
+
+#[tokio::test]
+async fn unsupported_elsewhere() -> Result<()> {
+    let engine = MemEngine::new();
+    assert!(matches!(engine.incr("hits".to_owned(), 1).await, Err(KvError::Unsupported(_))));
+    assert!(matches!(engine.append("log".to_owned(), "a".to_owned()).await, Err(KvError::Unsupported(_))));
+    Ok(())
+}
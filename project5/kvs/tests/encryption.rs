@@ -0,0 +1,116 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{Cipher, KvStore, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY_B: &str = "f0e0d0c0b0a090807060504030201000f1e1d1c1b1a191817161514131211101";
+
+// whether any log file contains `needle` in the clear
+fn plaintext_on_disk(dir: &Path, needle: &str) -> bool {
+    WalkDir::new(dir).into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .any(|e| {
+            let data = fs::read(e.path()).unwrap();
+            data.windows(needle.len()).any(|w| w == needle.as_bytes())
+        })
+}
+
+#[tokio::test]
+async fn encrypted_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_A)?)?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned()).await?;
+    store.set("other".to_owned(), "value".to_owned()).await?;
+    store.remove("other".to_owned()).await?;
+    assert_eq!(store.get("secret-key".to_owned()).await?, Some("secret-value".to_owned()));
+    drop(store);
+
+    assert!(!plaintext_on_disk(temp_dir.path(), "secret"));
+
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_A)?)?;
+    assert_eq!(store.get("secret-key".to_owned()).await?, Some("secret-value".to_owned()));
+    assert_eq!(store.get("other".to_owned()).await?, None);
+    drop(store);
+
+    // neither without a key nor with the wrong one
+    assert!(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).is_err());
+    assert!(KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_B)?).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 2, Cipher::from_hex(KEY_A)?)?;
+
+    // enough overwrites to pass the compaction limit a few times
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter)).await?;
+        }
+    }
+    assert!(store.stats().await?.compactions.unwrap() > 0);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id)).await?, Some("199".to_owned()));
+    }
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 2, Cipher::from_hex(KEY_A)?)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id)).await?, Some("199".to_owned()));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_A)?)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+
+    // files under key A are rewritten with key B on open
+    let cipher = Cipher::from_hex(KEY_B)?.with_old(Cipher::from_hex(KEY_A)?);
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, cipher)?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_A)?).is_err());
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_B)?)?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypt_plaintext_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "plain-value".to_owned()).await?;
+    drop(store);
+    assert!(plaintext_on_disk(temp_dir.path(), "plain-value"));
+
+    let store = KvStore::<RayonThreadPool>::open_encrypted(temp_dir.path(), 1, Cipher::from_hex(KEY_A)?)?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("plain-value".to_owned()));
+    assert!(!plaintext_on_disk(temp_dir.path(), "plain-value"));
+    Ok(())
+}
+
+#[test]
+fn keyfile_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hex_file = temp_dir.path().join("hex.key");
+    fs::write(&hex_file, format!("{}\n", KEY_A))?;
+    Cipher::from_keyfile(&hex_file)?;
+
+    let raw_file = temp_dir.path().join("raw.key");
+    fs::write(&raw_file, [7u8; 32])?;
+    Cipher::from_keyfile(&raw_file)?;
+
+    assert!(Cipher::from_hex("abcd").is_err());
+    assert!(Cipher::from_hex(&KEY_A.replace('0', "g")).is_err());
+    Ok(())
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{HttpServer, KvStore, KvsEngine, MemEngine, Result, MEMCACHE_KEYSPACE};
+use common::{serve_http, start_http};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// send a request on a kept-alive connection, returns the status and the JSON body
+async fn request(stream: &mut BufReader<TcpStream>, method: &str, path: &str, body: Option<Value>) -> (u16, Option<Value>) {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let req = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body);
+    stream.get_mut().write_all(req.as_bytes()).await.unwrap();
+
+    let mut status_line = String::new();
+    stream.read_line(&mut status_line).await.unwrap();
+    let status = status_line.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().unwrap();
+        }
+    }
+    if content_length == 0 {
+        return (status, None);
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.unwrap();
+    (status, Some(serde_json::from_slice(&body).unwrap()))
+}
+
+#[tokio::test]
+async fn http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4114";
+    let _stop = start_http(store.clone(), addr).await;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    assert_eq!(request(&mut stream, "GET", "/health", None).await, (200, Some(json!({ "status": "ok" }))));
+    assert_eq!(request(&mut stream, "PUT", "/kv/key1", Some(json!({ "value": "value1" }))).await, (204, None));
+    assert_eq!(
+        request(&mut stream, "GET", "/kv/key1", None).await,
+        (200, Some(json!({ "key": "key1", "value": "value1" })))
+    );
+    // keys are percent-decoded
+    assert_eq!(request(&mut stream, "PUT", "/kv/a%2Fb%20c", Some(json!({ "value": "v" }))).await, (204, None));
+    assert_eq!(store.get("a/b c".to_owned()).await?, Some("v".to_owned()));
+
+    let (status, body) = request(&mut stream, "GET", "/kv/missing", None).await;
+    assert_eq!(status, 404);
+    assert!(body.unwrap()["error"].is_string());
+    assert_eq!(request(&mut stream, "DELETE", "/kv/key1", None).await, (204, None));
+    assert_eq!(request(&mut stream, "DELETE", "/kv/key1", None).await.0, 404);
+    assert_eq!(request(&mut stream, "GET", "/kv/key1", None).await.0, 404);
+
+    let (status, body) = request(&mut stream, "GET", "/stats", None).await;
+    assert_eq!(status, 200);
+    let stats = body.unwrap();
+    assert_eq!(stats["engine"], "kvs");
+    assert_eq!(stats["keys"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.create_keyspace("users".to_owned()).await?;
+    store.create_keyspace(MEMCACHE_KEYSPACE.to_owned()).await?;
+    let addr = "127.0.0.1:4115";
+    let _stop = start_http(store.clone(), addr).await;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    assert_eq!(request(&mut stream, "PUT", "/kv/key", Some(json!({ "val": 1 }))).await.0, 400);
+    assert_eq!(request(&mut stream, "POST", "/kv/key", None).await.0, 405);
+    assert_eq!(request(&mut stream, "GET", "/nowhere", None).await.0, 404);
+    assert_eq!(request(&mut stream, "GET", "/kv/key?keyspace=nope", None).await.0, 404);
+    assert_eq!(request(&mut stream, "GET", "/kv/key?keyspace=memcached", None).await.0, 400);
+    assert_eq!(request(&mut stream, "PUT", "/kv/key?keyspace=users", Some(json!({ "value": "u" }))).await.0, 204);
+    assert_eq!(store.keyspace("users")?.get("key".to_owned()).await?, Some("u".to_owned()));
+    assert_eq!(store.get("key".to_owned()).await?, None);
+
+    // a malformed request gets a 400 and the connection is closed
+    stream.get_mut().write_all(b"NONSENSE\r\n\r\n").await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    assert!(reply.starts_with("HTTP/1.1 400 "));
+    assert!(reply.contains("\"error\""));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_body_limit() -> Result<()> {
+    let addr = "127.0.0.1:4128";
+    let _stop = serve_http(HttpServer::new(MemEngine::new()).with_max_body(32), addr).await;
+
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+    assert_eq!(request(&mut stream, "PUT", "/kv/key", Some(json!({ "value": "short" }))).await.0, 204);
+    let (status, body) = request(&mut stream, "PUT", "/kv/key", Some(json!({ "value": "x".repeat(32) }))).await;
+    assert_eq!(status, 413);
+    assert!(body.unwrap()["error"].is_string());
+
+    // a huge Content-Length is refused before anything is read
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(b"PUT /kv/key HTTP/1.1\r\nContent-Length: 1073741824\r\n\r\n").await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    assert!(reply.starts_with("HTTP/1.1 413 "), "{}", reply);
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, LsmEngine, MemEngine, SledEngine, Result};
+use tempfile::TempDir;
+
+// the same key in two keyspaces holds two values, dropping one keyspace leaves the other alone
+async fn check_isolation<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.create_keyspace("users".to_owned()).await?;
+    assert!(engine.create_keyspace("users".to_owned()).await.is_err());
+    assert!(engine.create_keyspace("default".to_owned()).await.is_err());
+    assert!(engine.create_keyspace("../escape".to_owned()).await.is_err());
+    assert_eq!(engine.list_keyspaces().await?, vec!["default".to_owned(), "users".to_owned()]);
+
+    let users = engine.keyspace("users")?;
+    engine.set("key1".to_owned(), "default1".to_owned()).await?;
+    users.set("key1".to_owned(), "users1".to_owned()).await?;
+    users.set("key2".to_owned(), "users2".to_owned()).await?;
+
+    assert_eq!(engine.get("key1".to_owned()).await?, Some("default1".to_owned()));
+    assert_eq!(users.get("key1".to_owned()).await?, Some("users1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned()).await?, None);
+    assert!(engine.remove("key2".to_owned()).await.is_err());
+    // not every engine counts its keys
+    if let Some(keys) = users.stats().await?.keys {
+        assert_eq!(keys, 2);
+    }
+
+    engine.drop_keyspace("users".to_owned()).await?;
+    assert!(engine.keyspace("users").is_err());
+    assert!(engine.drop_keyspace("users".to_owned()).await.is_err());
+    assert!(engine.drop_keyspace("default".to_owned()).await.is_err());
+    assert_eq!(engine.list_keyspaces().await?, vec!["default".to_owned()]);
+    assert_eq!(engine.get("key1".to_owned()).await?, Some("default1".to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kvs_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_isolation(KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?).await
+}
+
+#[tokio::test]
+async fn sled_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_isolation(SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?).await
+}
+
+#[tokio::test]
+async fn lsm_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_isolation(LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?).await
+}
+
+#[tokio::test]
+async fn memory_keyspaces() -> Result<()> {
+    check_isolation(MemEngine::new()).await
+}
+
+#[tokio::test]
+async fn kvs_keyspaces_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.create_keyspace("orders".to_owned()).await?;
+    store.keyspace("orders")?.set("key1".to_owned(), "value1".to_owned()).await?;
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.list_keyspaces().await?, vec!["default".to_owned(), "orders".to_owned()]);
+    assert_eq!(store.keyspace("orders")?.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    Ok(())
+}
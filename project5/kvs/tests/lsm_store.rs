@@ -0,0 +1,100 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{LsmEngine, KvsEngine, Result};
+use tempfile::TempDir;
+use futures::TryStreamExt;
+
+// Should get previously stored value
+#[tokio::test]
+async fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).await?, Some("value2".to_owned()));
+
+    // Open from disk again and check the wal is replayed
+    drop(store);
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).await?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(store.remove("key1".to_owned()).await.is_err());
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(store.remove("key1".to_owned()).await.is_ok());
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert!(store.remove("key1".to_owned()).await.is_err());
+
+    drop(store);
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+// Write enough data to flush several memtables and trigger a level-0 compaction,
+// then check overwritten and removed keys across reopen.
+#[tokio::test]
+async fn flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let padding = "x".repeat(1000);
+
+    for iter in 0..6 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}-{}", iter, padding)).await?;
+        }
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove(format!("key{}", key_id)).await?;
+    }
+
+    check_compacted(&store, &padding).await?;
+
+    let tables = std::fs::read_dir(temp_dir.path())?
+        .filter(|e| e.as_ref().unwrap().path().extension().map(|ext| ext == "sst").unwrap_or(false))
+        .count();
+    assert!(tables > 0 && tables < 6, "expected compacted tables, found {}", tables);
+
+    // merged in key order, removed keys left out
+    let pairs: Vec<(String, String)> = store.iter().try_collect().await?;
+    let mut keys: Vec<String> = (1..1000).step_by(2).map(|i| format!("key{}", i)).collect();
+    keys.sort();
+    assert_eq!(pairs.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(), keys);
+
+    drop(store);
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    check_compacted(&store, &padding).await?;
+    Ok(())
+}
+
+async fn check_compacted(store: &LsmEngine<RayonThreadPool>, padding: &str) -> Result<()> {
+    for key_id in 0..1000 {
+        let expected = if key_id % 2 == 0 { None } else { Some(format!("5-{}", padding)) };
+        assert_eq!(store.get(format!("key{}", key_id)).await?, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    assert!(store.compare_and_swap("key".to_owned(), None, Some("1".to_owned())).await?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, Some("2".to_owned())).await?);
+    assert!(store.compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("2".to_owned())).await?);
+    assert_eq!(store.get("key".to_owned()).await?, Some("2".to_owned()));
+    assert!(store.compare_and_swap("key".to_owned(), Some("2".to_owned()), None).await?);
+    assert_eq!(store.get("key".to_owned()).await?, None);
+    assert!(store.compare_and_swap("key".to_owned(), None, None).await?);
+    Ok(())
+}
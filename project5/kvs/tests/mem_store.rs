@@ -0,0 +1,71 @@
+use kvs::{MemEngine, EvictionPolicy, KvsEngine, Result};
+
+#[tokio::test]
+async fn get_set_remove() -> Result<()> {
+    let store = MemEngine::new();
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+
+    assert!(store.remove("key2".to_owned()).await.is_err());
+    store.remove("key1".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+// every entry takes 4 + 6 = 10 bytes, the limit fits three of them
+#[tokio::test]
+async fn lru_eviction() -> Result<()> {
+    let store = MemEngine::with_limit(Some(30), EvictionPolicy::Lru);
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i)).await?;
+    }
+    // key1 becomes the most recently used, key2 goes first
+    store.get("key1".to_owned()).await?;
+    store.set("key4".to_owned(), "value4".to_owned()).await?;
+
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned()).await?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned()).await?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn lfu_eviction() -> Result<()> {
+    let store = MemEngine::with_limit(Some(30), EvictionPolicy::Lfu);
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i)).await?;
+    }
+    store.get("key1".to_owned()).await?;
+    store.get("key1".to_owned()).await?;
+    store.get("key3".to_owned()).await?;
+    // key2 is the least frequently used even though it isn't the oldest access
+    store.get("key2".to_owned()).await?;
+    store.get("key3".to_owned()).await?;
+    store.set("key4".to_owned(), "value4".to_owned()).await?;
+
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned()).await?, Some("value3".to_owned()));
+
+    assert!(store.set("key5".to_owned(), "x".repeat(100)).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let store = MemEngine::new();
+
+    assert!(store.compare_and_swap("key".to_owned(), None, Some("1".to_owned())).await?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, Some("2".to_owned())).await?);
+    assert!(store.compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("2".to_owned())).await?);
+    assert_eq!(store.get("key".to_owned()).await?, Some("2".to_owned()));
+    assert!(store.compare_and_swap("key".to_owned(), Some("2".to_owned()), None).await?);
+    assert_eq!(store.get("key".to_owned()).await?, None);
+    assert!(store.compare_and_swap("key".to_owned(), None, None).await?);
+    Ok(())
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, LsmEngine, MemEngine, Result, SledEngine, MEMCACHE_KEYSPACE};
+use common::{expect, start_memcache};
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use std::time::Duration;
+
+/// the cas unique `gets` reports for `key`
+async fn cas_unique(stream: &mut BufReader<TcpStream>, key: &str) -> u64 {
+    stream.get_mut().write_all(format!("gets {}\r\n", key).as_bytes()).await.unwrap();
+    let mut line = String::new();
+    stream.read_line(&mut line).await.unwrap();
+    let unique = line.split_whitespace().nth(4).unwrap().parse().unwrap();
+    // the data line and END
+    for _ in 0..2 {
+        line.clear();
+        stream.read_line(&mut line).await.unwrap();
+    }
+    unique
+}
+
+#[tokio::test]
+async fn memcache_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_commands(KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?, "127.0.0.1:4116").await
+}
+
+#[tokio::test]
+async fn memcache_commands_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_commands(SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?, "127.0.0.1:4129").await
+}
+
+#[tokio::test]
+async fn memcache_commands_memory() -> Result<()> {
+    check_commands(MemEngine::new(), "127.0.0.1:4130").await
+}
+
+#[tokio::test]
+async fn memcache_commands_lsm() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_commands(LsmEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?, "127.0.0.1:4131").await
+}
+
+async fn check_commands<E: KvsEngine>(store: E, addr: &str) -> Result<()> {
+    let _stop = start_memcache(store.clone(), addr).await;
+    let mut stream = TcpStream::connect(addr).await?;
+
+    expect(&mut stream, "set key1 42 0 6\r\nvalue1\r\n", "STORED\r\n").await;
+    expect(&mut stream, "get key1\r\n", "VALUE key1 42 6\r\nvalue1\r\nEND\r\n").await;
+    expect(&mut stream, "get missing key1\r\n", "VALUE key1 42 6\r\nvalue1\r\nEND\r\n").await;
+    expect(&mut stream, "add key1 0 0 1\r\nx\r\n", "NOT_STORED\r\n").await;
+    expect(&mut stream, "add key2 0 0 1\r\nx\r\n", "STORED\r\n").await;
+    expect(&mut stream, "replace missing 0 0 1\r\nx\r\n", "NOT_STORED\r\n").await;
+    expect(&mut stream, "replace key2 7 0 1\r\ny\r\n", "STORED\r\n").await;
+    expect(&mut stream, "get key2\r\n", "VALUE key2 7 1\r\ny\r\nEND\r\n").await;
+    expect(&mut stream, "delete key2\r\n", "DELETED\r\n").await;
+    expect(&mut stream, "delete key2\r\n", "NOT_FOUND\r\n").await;
+
+    expect(&mut stream, "set n 0 0 2\r\n10\r\n", "STORED\r\n").await;
+    expect(&mut stream, "incr n 5\r\n", "15\r\n").await;
+    expect(&mut stream, "decr n 20\r\n", "0\r\n").await;
+    expect(&mut stream, "incr missing 1\r\n", "NOT_FOUND\r\n").await;
+    expect(&mut stream, "incr key1 1\r\n", "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n").await;
+
+    // noreply suppresses the reply, the next command's reply comes first
+    expect(&mut stream, "set quiet 0 0 1 noreply\r\nq\r\nget quiet\r\n", "VALUE quiet 0 1\r\nq\r\nEND\r\n").await;
+    // items are kept apart from the keys of the other protocols
+    store.set("quiet".to_owned(), "loud".to_owned()).await?;
+    expect(&mut stream, "get quiet\r\n", "VALUE quiet 0 1\r\nq\r\nEND\r\n").await;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    assert!(store.keyspace(MEMCACHE_KEYSPACE)?.get("key1".to_owned()).await?.is_some());
+    expect(&mut stream, "version\r\n", &format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))).await;
+    expect(&mut stream, "flush_all\r\n", "ERROR\r\n").await;
+    expect(&mut stream, "get\r\n", "CLIENT_ERROR bad command line format\r\n").await;
+
+    // a bad data block closes the connection
+    expect(&mut stream, "set key3 0 0 1\r\nxyz\r\n", "CLIENT_ERROR bad data chunk\r\n").await;
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await?;
+    assert!(rest.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn memcache_cas_and_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4117";
+    let _stop = start_memcache(store, addr).await;
+    let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+
+    expect(stream.get_mut(), "set key 0 0 1\r\na\r\n", "STORED\r\n").await;
+    let unique = cas_unique(&mut stream, "key").await;
+    expect(stream.get_mut(), &format!("cas key 0 0 1 {}\r\nb\r\n", unique), "STORED\r\n").await;
+    // the unique changed with the last write
+    expect(stream.get_mut(), &format!("cas key 0 0 1 {}\r\nc\r\n", unique), "EXISTS\r\n").await;
+    expect(stream.get_mut(), "cas missing 0 0 1 1\r\nc\r\n", "NOT_FOUND\r\n").await;
+    expect(stream.get_mut(), "get key\r\n", "VALUE key 0 1\r\nb\r\nEND\r\n").await;
+    assert_ne!(cas_unique(&mut stream, "key").await, unique);
+
+    expect(stream.get_mut(), "set short 0 2 1\r\ns\r\n", "STORED\r\n").await;
+    expect(stream.get_mut(), "set gone 0 -1 1\r\ng\r\n", "STORED\r\n").await;
+    expect(stream.get_mut(), "get short gone\r\n", "VALUE short 0 1\r\ns\r\nEND\r\n").await;
+    tokio::time::sleep(Duration::from_millis(3100)).await;
+    expect(stream.get_mut(), "get short\r\n", "END\r\n").await;
+    // expired items can be added again
+    expect(stream.get_mut(), "add short 0 0 1\r\nt\r\n", "STORED\r\n").await;
+    expect(stream.get_mut(), "delete gone\r\n", "NOT_FOUND\r\n").await;
+
+    Ok(())
+}
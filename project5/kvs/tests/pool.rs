@@ -0,0 +1,121 @@
+mod common;
+
+use kvs::{Capabilities, Codec, ClientPool, HelloReply, MemEngine, PoolOptions, Result, PROTOCOL_VERSION};
+use futures::{future, SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_serde::SymmetricallyFramed;
+use tokio_serde::formats::SymmetricalBincode;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use std::time::{Duration, Instant};
+
+/// run a server closing idle connections after `idle`, until the returned sender is used or dropped
+async fn start_server(addr: &str, idle: Duration) -> oneshot::Sender<()> {
+    common::serve(common::new_server(MemEngine::new()).with_idle_timeout(idle), addr).await
+}
+
+#[tokio::test]
+async fn shared_handles() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    let _stop = start_server(addr, Duration::from_secs(60)).await;
+    let options = PoolOptions { min_connections: 2, max_connections: 4, ..PoolOptions::default() };
+    let pool = ClientPool::connect(addr, options).await?;
+    assert_eq!(pool.idle_connections(), 2);
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                pool.set(format!("key{}", i), format!("value{}", i)).await?;
+                pool.get(format!("key{}", i)).await
+            })
+        })
+        .collect();
+    for (i, res) in future::join_all(tasks).await.into_iter().enumerate() {
+        assert_eq!(res.unwrap()?, Some(format!("value{}", i)));
+    }
+    // never more than max_connections
+    assert!(pool.idle_connections() <= 4);
+
+    // a checked out connection offers the whole client API
+    let client = pool.acquire().await?;
+    assert!(client.incr("key0".to_owned(), 1).await.is_err());
+    client.ping().await?;
+    drop(client);
+    pool.remove("key0".to_owned()).await?;
+    assert_eq!(pool.get("key0".to_owned()).await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn replaces_closed_connections() -> Result<()> {
+    let addr = "127.0.0.1:4110";
+    // the server hangs up on the pool's idle connections
+    let _stop = start_server(addr, Duration::from_millis(200)).await;
+    // no health check in between, the pool has to notice on its own
+    let options = PoolOptions {
+        min_connections: 2,
+        health_check_interval: Duration::from_secs(60),
+        ..PoolOptions::default()
+    };
+    let pool = ClientPool::connect(addr, options).await?;
+    pool.set("key".to_owned(), "value".to_owned()).await?;
+
+    wait_until(|| pool.idle_connections() == 0).await;
+    assert_eq!(pool.get("key".to_owned()).await?, Some("value".to_owned()));
+    assert_eq!(pool.idle_connections(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_gives_back_its_connection() -> Result<()> {
+    let addr = "127.0.0.1:4134";
+    // a server that completes the hello, then hangs up on the first request
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+                // the hello
+                framed.next().await;
+                let mut reply = SymmetricallyFramed::new(framed, SymmetricalBincode::<HelloReply>::default());
+                let accepted = HelloReply::Accepted { version: PROTOCOL_VERSION, capabilities: Capabilities::empty(), codec: Codec::Bincode };
+                reply.send(accepted).await.unwrap();
+                reply.into_inner().next().await;
+            });
+        }
+    });
+
+    // the retry needs the permit the failed attempt holds
+    let options = PoolOptions { min_connections: 1, max_connections: 1, ..PoolOptions::default() };
+    let pool = ClientPool::connect(addr, options).await?;
+    let get = tokio::time::timeout(Duration::from_secs(3), pool.get("key".to_owned())).await;
+    assert!(get.expect("the retry hung").is_err());
+    let set = tokio::time::timeout(Duration::from_secs(3), pool.set("key".to_owned(), "value".to_owned())).await;
+    assert!(set.expect("the retry hung").is_err());
+
+    Ok(())
+}
+
+/// poll `done` until it holds, failing after a few seconds
+async fn wait_until(done: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn connect_errors() {
+    let options = PoolOptions { connect_timeout: Duration::from_millis(200), ..PoolOptions::default() };
+    let start = Instant::now();
+    // nothing listens on this port
+    assert!(ClientPool::connect("127.0.0.1:4111", options).await.is_err());
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let options = PoolOptions { min_connections: 2, max_connections: 1, ..PoolOptions::default() };
+    assert!(ClientPool::connect("127.0.0.1:4109", options).await.is_err());
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, Result};
+use common::{expect, start_resp};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4112";
+    let _stop = start_resp(store.clone(), addr).await;
+    let mut stream = TcpStream::connect(addr).await?;
+
+    expect(&mut stream, "*1\r\n$4\r\nPING\r\n", "+PONG\r\n").await;
+    expect(&mut stream, "*2\r\n$4\r\nping\r\n$2\r\nhi\r\n", "$2\r\nhi\r\n").await;
+    expect(&mut stream, "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n", "+OK\r\n").await;
+    expect(&mut stream, "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", "$6\r\nvalue1\r\n").await;
+    expect(&mut stream, "*2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n", "$-1\r\n").await;
+    // values written over RESP are regular engine values
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+
+    expect(&mut stream, "*4\r\n$4\r\nMGET\r\n$4\r\nkey1\r\n$1\r\nx\r\n$4\r\nkey2\r\n",
+        "*3\r\n$6\r\nvalue1\r\n$-1\r\n$6\r\nvalue2\r\n").await;
+    expect(&mut stream, "*4\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$1\r\nx\r\n", ":2\r\n").await;
+    expect(&mut stream, "*3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$1\r\nx\r\n", ":1\r\n").await;
+    expect(&mut stream, "*2\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n", ":0\r\n").await;
+
+    // pipelined commands are answered in order
+    expect(&mut stream, "*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n", "+PONG\r\n$6\r\nvalue2\r\n").await;
+    // inline commands, as typed into telnet
+    expect(&mut stream, "GET key2\r\n", "$6\r\nvalue2\r\n").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn resp_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4113";
+    let _stop = start_resp(store, addr).await;
+    let mut stream = TcpStream::connect(addr).await?;
+
+    expect(&mut stream, "*2\r\n$4\r\nINCR\r\n$1\r\nx\r\n", "-ERR unknown command 'incr'\r\n").await;
+    expect(&mut stream, "*1\r\n$3\r\nGET\r\n", "-ERR wrong number of arguments for 'get' command\r\n").await;
+    expect(&mut stream, "*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$2\r\n10\r\n", "-ERR syntax error\r\n").await;
+    // the connection is still usable after errors
+    expect(&mut stream, "PING\r\n", "+PONG\r\n").await;
+
+    let mut info = String::new();
+    stream.write_all(b"*1\r\n$4\r\nINFO\r\n").await?;
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    info.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+    assert!(info.starts_with('$'));
+    assert!(info.contains("engine:kvs\r\n"));
+
+    // a malformed frame is answered and the connection closed
+    expect(&mut stream, "*1\r\n#4\r\n", "-ERR Protocol error: expected '$'\r\n").await;
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    let mut stream = TcpStream::connect(addr).await?;
+    expect(&mut stream, "QUIT\r\n", "+OK\r\n").await;
+    assert_eq!(stream.read(&mut buf).await?, 0);
+
+    Ok(())
+}
@@ -0,0 +1,273 @@
+mod common;
+
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{BatchOp, Client, KvStore, KvsEngine, MemEngine, Result, SledEngine, WatchEvent, MEMCACHE_KEYSPACE};
+use common::start_server;
+use kvs::{Capabilities, Codec, ConnectOptions, Hello, HelloReply, KvError, Request, Tagged, PROTOCOL_VERSION};
+use futures::{future, SinkExt, StreamExt, TryStreamExt};
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_serde::formats::SymmetricalBincode;
+use tokio_serde::SymmetricallyFramed;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tempfile::TempDir;
+use std::time::Duration;
+
+#[tokio::test]
+async fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4101";
+    let _stop = start_server(store, addr).await;
+
+    let mut events = Client::connect(addr).await?.watch("app/".to_owned()).await?;
+
+    let client = Client::connect(addr).await?;
+    client.set("app/a".to_owned(), "1".to_owned()).await?;
+    let client = Client::connect(addr).await?;
+    client.set("other".to_owned(), "2".to_owned()).await?;
+    let client = Client::connect(addr).await?;
+    client.remove("app/a".to_owned()).await?;
+
+    let first = events.try_next().await?.unwrap();
+    assert_eq!(first, WatchEvent { seq: 1, key: "app/a".to_owned(), value: Some("1".to_owned()) });
+    // `other` isn't delivered but still takes a sequence number
+    let second = events.try_next().await?.unwrap();
+    assert_eq!(second, WatchEvent { seq: 3, key: "app/a".to_owned(), value: None });
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keyspace_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.create_keyspace(MEMCACHE_KEYSPACE.to_owned()).await?;
+    let addr = "127.0.0.1:4102";
+    let _stop = start_server(store, addr).await;
+
+    Client::connect(addr).await?.create_keyspace("team-a".to_owned()).await?;
+    let mut client = Client::connect(addr).await?;
+    client.use_keyspace("team-a");
+    client.set("key1".to_owned(), "a".to_owned()).await?;
+
+    let mut client = Client::connect(addr).await?;
+    client.use_keyspace("team-a");
+    assert_eq!(client.get("key1".to_owned()).await?, Some("a".to_owned()));
+    assert_eq!(Client::connect(addr).await?.get("key1".to_owned()).await?, None);
+    assert_eq!(
+        Client::connect(addr).await?.list_keyspaces().await?,
+        vec!["default".to_owned(), "team-a".to_owned()]
+    );
+
+    let mut client = Client::connect(addr).await?;
+    client.use_keyspace("missing");
+    assert!(client.get("key1".to_owned()).await.is_err());
+
+    // the memcached listener's keyspace is neither listed nor open to clients
+    let mut client = Client::connect(addr).await?;
+    client.use_keyspace(MEMCACHE_KEYSPACE);
+    assert!(client.get("key1".to_owned()).await.is_err());
+    assert!(client.set("key1".to_owned(), "a".to_owned()).await.is_err());
+    assert!(client.drop_keyspace(MEMCACHE_KEYSPACE.to_owned()).await.is_err());
+
+    Client::connect(addr).await?.drop_keyspace("team-a".to_owned()).await?;
+    let mut client = Client::connect(addr).await?;
+    client.use_keyspace("team-a");
+    assert!(client.get("key1".to_owned()).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_native_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4103";
+    let _stop = start_server(store, addr).await;
+
+    let caps = Client::connect(addr).await?.capabilities();
+    assert!(caps.contains(Capabilities::BATCH | Capabilities::SCAN));
+    assert!(!caps.contains(Capabilities::TTL));
+
+    let ops = vec![
+        BatchOp::Set { key: "k1".to_owned(), value: "v1".to_owned() },
+        BatchOp::Set { key: "k2".to_owned(), value: "v2".to_owned() },
+    ];
+    Client::connect(addr).await?.batch(ops).await?;
+    assert!(Client::connect(addr).await?.compare_and_swap("k1".to_owned(), Some("v1".to_owned()), Some("v3".to_owned())).await?);
+    assert!(!Client::connect(addr).await?.compare_and_swap("k2".to_owned(), None, Some("v4".to_owned())).await?);
+    assert_eq!(
+        Client::connect(addr).await?.scan("k".to_owned(), None, None).await?,
+        vec![("k1".to_owned(), "v3".to_owned()), ("k2".to_owned(), "v2".to_owned())]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn counter_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4104";
+    let _stop = start_server(store, addr).await;
+
+    assert_eq!(Client::connect(addr).await?.incr("hits".to_owned(), 3).await?, 3);
+    assert_eq!(Client::connect(addr).await?.incr("hits".to_owned(), -1).await?, 2);
+    assert_eq!(Client::connect(addr).await?.append("log".to_owned(), "ab".to_owned()).await?, 2);
+    assert_eq!(Client::connect(addr).await?.append("log".to_owned(), "cd".to_owned()).await?, 4);
+    assert!(Client::connect(addr).await?.incr("log".to_owned(), 1).await.is_err());
+    assert_eq!(Client::connect(addr).await?.get("log".to_owned()).await?, Some("abcd".to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multi_requests() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let _stop = start_server(MemEngine::new(), addr).await;
+
+    let pairs = vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())];
+    Client::connect(addr).await?.set_many(pairs).await?;
+    assert_eq!(
+        Client::connect(addr).await?.get_many(vec!["b".to_owned(), "c".to_owned(), "a".to_owned()]).await?,
+        vec![Some("2".to_owned()), None, Some("1".to_owned())]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn many_requests_one_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4106";
+    let _stop = start_server(store, addr).await;
+
+    let client = Client::connect(addr).await?;
+    for i in 0..2000 {
+        client.set(format!("key{}", i), format!("value{}", i)).await?;
+    }
+    for i in 0..2000 {
+        assert_eq!(client.get(format!("key{}", i)).await?, Some(format!("value{}", i)));
+    }
+    // errors don't close the connection
+    assert!(client.remove("missing".to_owned()).await.is_err());
+    assert_eq!(client.incr("hits".to_owned(), 1).await?, 1);
+    assert_eq!(client.stats().await?.keys, Some(2001));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn idle_timeout() -> Result<()> {
+    let addr = "127.0.0.1:4107";
+    let server = common::new_server(MemEngine::new()).with_idle_timeout(Duration::from_millis(300));
+    let _stop = common::serve(server, addr).await;
+
+    let client = Client::connect(addr).await?;
+    for _ in 0..3 {
+        client.set("key".to_owned(), "value".to_owned()).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(client.get("key".to_owned()).await.is_err());
+    assert_eq!(Client::connect(addr).await?.get("key".to_owned()).await?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let addr = "127.0.0.1:4108";
+    let _stop = start_server(store, addr).await;
+
+    // all requests are in flight at once, answers may come back in any order
+    let client = Client::connect(addr).await?;
+    let sets = (0..1000).map(|i| client.set(format!("key{}", i), format!("value{}", i)));
+    future::try_join_all(sets).await?;
+
+    let gets = (0..1000).map(|i| client.get(format!("key{}", i)));
+    let values = future::try_join_all(gets).await?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+
+    let (missing, stats) = future::join(client.remove("missing".to_owned()), client.stats()).await;
+    assert!(missing.is_err());
+    assert_eq!(stats?.keys, Some(1000));
+
+    Ok(())
+}
+
+/// send `msg` as the first frame of a new connection, returns the reply to it
+async fn first_exchange<T: Serialize + Unpin>(addr: &str, msg: T) -> Result<Option<HelloReply>> {
+    let framed = Framed::new(TcpStream::connect(addr).await?, LengthDelimitedCodec::new());
+    let mut out = SymmetricallyFramed::new(framed, SymmetricalBincode::<T>::default());
+    out.send(msg).await?;
+    let mut reply = SymmetricallyFramed::new(out.into_inner(), SymmetricalBincode::<HelloReply>::default());
+    Ok(reply.try_next().await?)
+}
+
+#[tokio::test]
+async fn handshake() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    let addr = "127.0.0.1:4118";
+    let _stop = start_server(store, addr).await;
+
+    // the kvs engine has none of the optional requests, the client knows without asking
+    let client = Client::connect(addr).await?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    assert_eq!(client.capabilities(), Capabilities::empty());
+    assert!(matches!(client.batch(Vec::new()).await, Err(KvError::Unsupported(_))));
+    client.set("key".to_owned(), "value".to_owned()).await?;
+
+    // a newer client is answered with the server's version
+    match first_exchange(addr, Hello::new(PROTOCOL_VERSION + 1, Capabilities::all(), None)).await? {
+        Some(HelloReply::Accepted { version, .. }) => assert_eq!(version, PROTOCOL_VERSION),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    // too old a version, or no hello at all, is refused
+    match first_exchange(addr, Hello::new(0, Capabilities::all(), None)).await? {
+        Some(HelloReply::Rejected(msg)) => assert!(msg.contains("version 0")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    let old_client = Tagged { id: 0, msg: Request::Get { keyspace: None, key: "key".to_owned() } };
+    assert!(matches!(first_exchange(addr, old_client).await?, Some(HelloReply::Rejected(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn codecs() -> Result<()> {
+    let addr = "127.0.0.1:4119";
+    let _stop = common::serve(common::new_server(MemEngine::new()).with_codec(Codec::Json), addr).await;
+
+    // the listener's default unless the client picks one
+    let client = Client::connect(addr).await?;
+    assert_eq!(client.codec(), Codec::Json);
+    client.set("key".to_owned(), "json".to_owned()).await?;
+    for codec in [Codec::Bincode, Codec::Json, Codec::MessagePack] {
+        let client = Client::connect_with(addr, ConnectOptions { codec: Some(codec), ..Default::default() }).await?;
+        assert_eq!(client.codec(), codec);
+        assert_eq!(client.get("key".to_owned()).await?, Some("json".to_owned()));
+        client.set_many(vec![("a".to_owned(), "1".to_owned())]).await?;
+        assert_eq!(client.stats().await?.keys, Some(2));
+        assert!(client.remove("missing".to_owned()).await.is_err());
+    }
+
+    // JSON frames are plain text on the wire
+    let framed = Framed::new(TcpStream::connect(addr).await?, LengthDelimitedCodec::new());
+    let mut hello = SymmetricallyFramed::new(framed, SymmetricalBincode::<Hello>::default());
+    hello.send(Hello::new(PROTOCOL_VERSION, Capabilities::empty(), None)).await?;
+    let mut framed = hello.into_inner();
+    framed.next().await.unwrap()?;
+    framed.send(r#"{"id":7,"msg":{"Get":{"keyspace":null,"key":"key"}}}"#.into()).await?;
+    let frame = framed.next().await.unwrap()?;
+    assert_eq!(std::str::from_utf8(&frame).unwrap(), r#"{"id":7,"msg":{"Get":"json"}}"#);
+
+    Ok(())
+}
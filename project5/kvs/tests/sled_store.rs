@@ -0,0 +1,103 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{BatchOp, KvStore, KvsEngine, SledEngine, SledMode, SledOptions, Result};
+use tempfile::TempDir;
+
+fn set(key: &str, value: &str) -> BatchOp {
+    BatchOp::Set { key: key.to_owned(), value: value.to_owned() }
+}
+
+fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[tokio::test]
+async fn tuned_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        cache_capacity: Some(16 * 1024 * 1024),
+        flush_every_ms: Some(100),
+        compression: Some(3),
+        mode: Some(SledMode::LowSpace),
+    };
+    let store = SledEngine::<RayonThreadPool>::open_with(temp_dir.path(), 2, options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    store.remove("key1".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn open_with_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || SledOptions {
+        cache_capacity: Some(1024 * 1024),
+        flush_every_ms: Some(50),
+        compression: Some(5),
+        mode: Some(SledMode::HighThroughput),
+    };
+    let store = SledEngine::<RayonThreadPool>::open_with(temp_dir.path(), 2, options())?;
+    store.set("key1".to_owned(), "value1".repeat(100)).await?;
+    drop(store);
+
+    let store = SledEngine::<RayonThreadPool>::open_with(temp_dir.path(), 2, options())?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".repeat(100)));
+    drop(store);
+
+    // sled keeps the compression setting and refuses to open the files without it
+    assert!(SledEngine::<RayonThreadPool>::open_with(temp_dir.path(), 2, SledOptions::default()).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn batch_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    store.batch(vec![set("a", "1"), set("b", "2"), BatchOp::Remove { key: "a".to_owned() }]).await?;
+    assert_eq!(store.get("a".to_owned()).await?, None);
+    assert_eq!(store.get("b".to_owned()).await?, Some("2".to_owned()));
+
+    // the missing key aborts the whole batch
+    assert!(store.batch(vec![set("c", "3"), BatchOp::Remove { key: "missing".to_owned() }]).await.is_err());
+    assert_eq!(store.get("c".to_owned()).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    assert!(store.compare_and_swap("key".to_owned(), None, Some("1".to_owned())).await?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, Some("2".to_owned())).await?);
+    assert!(store.compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("2".to_owned())).await?);
+    assert_eq!(store.get("key".to_owned()).await?, Some("2".to_owned()));
+    assert!(store.compare_and_swap("key".to_owned(), Some("2".to_owned()), None).await?);
+    assert_eq!(store.get("key".to_owned()).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    for key in ["a", "b", "c", "d"].iter() {
+        store.set(key.to_string(), key.to_uppercase()).await?;
+    }
+
+    assert_eq!(store.scan("b".to_owned(), Some("d".to_owned()), None).await?, pairs(&[("b", "B"), ("c", "C")]));
+    assert_eq!(store.scan("b".to_owned(), None, Some(2)).await?, pairs(&[("b", "B"), ("c", "C")]));
+    assert_eq!(store.scan("".to_owned(), None, None).await?.len(), 4);
+    assert!(store.scan("d".to_owned(), Some("a".to_owned()), None).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsupported_elsewhere() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(store.batch(vec![set("a", "1")]).await.is_err());
+    assert!(store.scan("".to_owned(), None, None).await.is_err());
+    Ok(())
+}
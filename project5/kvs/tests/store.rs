@@ -1,11 +1,14 @@
 use crossbeam_utils::sync::WaitGroup;
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvError, Result};
+use kvs::{KvStore, KvOptions, KvsEngine, KvError, Result};
 use tempfile::TempDir;
 use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 use tokio::sync::Barrier;
+use std::fs;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures::TryStreamExt;
 
 // Should get previously stored value
 // #[tokio::test]
@@ -236,4 +239,300 @@ async fn concurrent_get() -> Result<()> {
     // }))?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key1".to_owned(), "value2".to_owned()).await?;
+    store.set("key2".to_owned(), "value3".to_owned()).await?;
+
+    let stats = store.stats().await?;
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.keys, Some(2));
+    assert!(stats.uncompacted.unwrap() > 0);
+    assert!(stats.total_bytes.unwrap() > stats.uncompacted.unwrap());
+    assert_eq!(stats.generations, Some(1));
+    assert_eq!(stats.current_gen, Some(1));
+    assert_eq!(stats.compactions, Some(0));
+    assert_eq!(stats.last_compaction, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn iter_all_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i)).await?;
+    }
+    store.set("key0".to_owned(), "new".to_owned()).await?;
+    store.remove("key1".to_owned()).await?;
+
+    let mut pairs: Vec<(String, String)> = store.iter().try_collect().await?;
+    pairs.sort();
+    let mut expected: Vec<(String, String)> = (2..1000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    expected.push(("key0".to_owned(), "new".to_owned()));
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    Ok(())
+}
+
+fn sharded(shards: usize) -> KvOptions {
+    KvOptions { shards, ..KvOptions::default() }
+}
+
+#[tokio::test]
+async fn sharded_concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 8, sharded(4))?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        tokio::spawn(async move {
+            store
+                .set(format!("key{}", i), format!("value{}", i)).await.unwrap();
+            barrier.wait().await;
+        });
+    }
+    barrier.wait().await;
+    store.remove("key0".to_owned()).await?;
+
+    let stats = store.stats().await?;
+    assert_eq!(stats.keys, Some(999));
+    assert_eq!(stats.generations, Some(4));
+    assert_eq!(store.iter().try_collect::<Vec<_>>().await?.len(), 999);
+    drop(store);
+
+    // every shard keeps its own log files
+    for i in 0..4 {
+        assert!(temp_dir.path().join(format!("shard-{}", i)).join("1").is_file());
+    }
+
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, sharded(4))?;
+    assert_eq!(store.get("key0".to_owned()).await?, None);
+    for i in 1..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    drop(store);
+
+    // keys would end up in the wrong shards
+    assert!(KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, sharded(2)).is_err());
+    assert!(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).is_err());
+
+    Ok(())
+}
+
+// the shard of a key is part of the on-disk format, existing stores would
+// lose track of their keys if it changed
+#[tokio::test]
+async fn shard_layout_is_stable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, sharded(4))?;
+    let expected = [("a", 0), ("b", 1), ("kvs", 1), ("key2", 2), ("key1", 3), ("hello", 3)];
+    for (key, _) in expected {
+        store.set(key.to_owned(), "value".to_owned()).await?;
+    }
+    drop(store);
+
+    for (key, shard) in expected {
+        for i in 0..4 {
+            let log = fs::read_to_string(temp_dir.path().join(format!("shard-{}", i)).join("1"))?;
+            assert_eq!(log.contains(&format!("\"key\":\"{}\"", key)), i == shard, "{} in shard {}", key, i);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sharded_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 4, sharded(2))?;
+
+    let padding = "x".repeat(100);
+    for iter in 0..400 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, padding)).await?;
+        }
+    }
+    // each shard compacts on its own
+    assert!(store.stats().await?.compactions.unwrap() >= 2);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id)).await?, Some(format!("399{}", padding)));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..100 {
+        store.set(format!("cold{}", i), format!("value{}", i)).await?;
+    }
+    drop(store);
+
+    // the cold keys sit in a clean file 1, the removal and the garbage go to file 2
+    let cold_file = temp_dir.path().join("1");
+    let cold_len = fs::metadata(&cold_file)?.len();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.remove("cold0".to_owned()).await?;
+    let padding = "x".repeat(1000);
+    for i in 0..2000 {
+        store.set("hot".to_owned(), format!("{}{}", i, padding)).await?;
+    }
+
+    let stats = store.stats().await?;
+    assert!(stats.compactions.unwrap() > 0);
+    assert!(stats.uncompacted.unwrap() < 1024 * 1024);
+    assert!(cold_file.is_file());
+    assert_eq!(fs::metadata(&cold_file)?.len(), cold_len);
+    assert_eq!(store.get("hot".to_owned()).await?, Some(format!("1999{}", padding)));
+    drop(store);
+
+    // the removal was carried forward, otherwise file 1 would bring cold0 back
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    // and counted as dead bytes as when the files are loaded
+    assert_eq!(store.stats().await?.uncompacted, stats.uncompacted);
+    assert_eq!(store.get("cold0".to_owned()).await?, None);
+    for i in 1..100 {
+        assert_eq!(store.get(format!("cold{}", i)).await?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("hot".to_owned()).await?, Some(format!("1999{}", padding)));
+
+    Ok(())
+}
+
+// removals that still hide keys in an older file can't be dropped, they
+// mustn't make every write compact them again
+#[tokio::test]
+async fn kept_removals_dont_trigger_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    // long keys make over 1MiB of removals, the large value keeps file 1 below the garbage ratio
+    let key = |i: usize| format!("{}{}", i, "k".repeat(1000));
+    for i in 0..1100 {
+        store.set(key(i), "v".to_owned()).await?;
+    }
+    store.set("large".to_owned(), "x".repeat(3 * 1024 * 1024)).await?;
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..1100 {
+        store.remove(key(i)).await?;
+    }
+    let compactions = store.stats().await?.compactions;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned()).await?;
+    }
+    assert_eq!(store.stats().await?.compactions, compactions);
+    assert_eq!(compactions, Some(0));
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(key(0)).await?, None);
+    assert_eq!(store.get("large".to_owned()).await?.map(|v| v.len()), Some(3 * 1024 * 1024));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvOptions { compaction_rate: Some(512 * 1024), ..KvOptions::default() };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 1, options)?;
+
+    // over 1MiB is read back at 512KiB/s after a burst of 512KiB
+    let padding = "x".repeat(1000);
+    let start = Instant::now();
+    for i in 0..1100 {
+        store.set("hot".to_owned(), format!("{}{}", i, padding)).await?;
+    }
+    // the compaction runs in the background
+    let deadline = start + Duration::from_secs(30);
+    while store.stats().await?.compactions == Some(0) {
+        assert!(Instant::now() < deadline, "compaction didn't finish");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(store.get("hot".to_owned()).await?, Some(format!("{}{}", 1099, padding)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn requests_during_slow_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvOptions { compaction_rate: Some(64 * 1024), ..KvOptions::default() };
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 2, options)?;
+
+    // over 1MiB of garbage takes the compaction well over 10 seconds
+    let padding = "x".repeat(1000);
+    for i in 0..1100 {
+        store.set("hot".to_owned(), format!("{}{}", i, padding)).await?;
+    }
+
+    let start = Instant::now();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned()).await?;
+        assert_eq!(store.get(format!("key{}", i)).await?, Some("value".to_owned()));
+        assert_eq!(store.get("hot".to_owned()).await?, Some(format!("{}{}", 1099, padding)));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(store.stats().await?.compactions, Some(0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_and_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with(temp_dir.path(), 2, sharded(4))?;
+
+    let mut pairs: Vec<_> = (0..100).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+    // later pairs win
+    pairs.push(("key0".to_owned(), "last".to_owned()));
+    store.set_many(pairs).await?;
+
+    let keys = vec!["key0".to_owned(), "missing".to_owned(), "key99".to_owned(), "key0".to_owned()];
+    assert_eq!(
+        store.get_many(keys).await?,
+        vec![Some("last".to_owned()), None, Some("value99".to_owned()), Some("last".to_owned())]
+    );
+    assert_eq!(store.get_many(Vec::new()).await?, Vec::<Option<String>>::new());
+    assert_eq!(store.stats().await?.keys, Some(100));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    assert!(store.compare_and_swap("key".to_owned(), None, Some("1".to_owned())).await?);
+    assert!(!store.compare_and_swap("key".to_owned(), None, Some("2".to_owned())).await?);
+    assert!(store.compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("2".to_owned())).await?);
+    assert_eq!(store.get("key".to_owned()).await?, Some("2".to_owned()));
+    assert!(store.compare_and_swap("key".to_owned(), Some("2".to_owned()), None).await?);
+    assert_eq!(store.get("key".to_owned()).await?, None);
+    // swapping an absent key for nothing is a no-op that succeeds
+    assert!(store.compare_and_swap("key".to_owned(), None, None).await?);
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use kvs::{Client, ClientTls, ConnectOptions, MemEngine, Result, ServerTls};
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// PEM files of a CA and the server and client certificates it signed
+struct TestPki {
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+/// a fresh CA with a server certificate for `localhost` and a client certificate
+fn generate_pki(dir: &Path) -> TestPki {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "kvs test CA");
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let leaf = |name: &str, usage: ExtendedKeyUsagePurpose| {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_owned()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    };
+    let (server_cert, server_key) = leaf("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+    let (client_cert, client_key) = leaf("client", ExtendedKeyUsagePurpose::ClientAuth);
+
+    let write = |name: &str, pem: &str| {
+        let path = dir.join(name);
+        fs::write(&path, pem).unwrap();
+        path
+    };
+    TestPki {
+        ca: write("ca.pem", &ca.pem()),
+        server_cert: write("server.pem", &server_cert),
+        server_key: write("server.key", &server_key),
+        client_cert: write("client.pem", &client_cert),
+        client_key: write("client.key", &client_key),
+    }
+}
+
+fn client_tls(pki: &TestPki, server_name: &str) -> ClientTls {
+    ClientTls { ca_file: pki.ca.clone(), server_name: server_name.to_owned(), cert_file: None, key_file: None }
+}
+
+async fn connect(addr: &str, tls: Option<ClientTls>) -> Result<Client> {
+    Client::connect_with(addr, ConnectOptions { tls, ..Default::default() }).await
+}
+
+#[tokio::test]
+async fn tls_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pki = generate_pki(temp_dir.path());
+    let addr = "127.0.0.1:4120";
+    let tls = ServerTls { cert_file: pki.server_cert.clone(), key_file: pki.server_key.clone(), client_ca_file: None };
+    let _stop = common::serve(common::new_server(MemEngine::new()).with_tls(&tls)?, addr).await;
+
+    let client = connect(addr, Some(client_tls(&pki, "localhost"))).await?;
+    client.set("key".to_owned(), "secret".to_owned()).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some("secret".to_owned()));
+
+    // plaintext clients and certificates for another name are refused
+    assert!(connect(addr, None).await.is_err());
+    assert!(connect(addr, Some(client_tls(&pki, "example.com"))).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn tls_client_certificates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pki = generate_pki(temp_dir.path());
+    let addr = "127.0.0.1:4121";
+    let tls = ServerTls {
+        cert_file: pki.server_cert.clone(),
+        key_file: pki.server_key.clone(),
+        client_ca_file: Some(pki.ca.clone()),
+    };
+    let _stop = common::serve(common::new_server(MemEngine::new()).with_tls(&tls)?, addr).await;
+
+    assert!(connect(addr, Some(client_tls(&pki, "localhost"))).await.is_err());
+
+    let with_cert = ClientTls {
+        cert_file: Some(pki.client_cert.clone()),
+        key_file: Some(pki.client_key.clone()),
+        ..client_tls(&pki, "localhost")
+    };
+    let client = connect(addr, Some(with_cert)).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn tls_cli() {
+    let temp_dir = TempDir::new().unwrap();
+    let pki = generate_pki(temp_dir.path());
+    let addr = "127.0.0.1:4122";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .arg("--tls-cert").arg(&pki.server_cert)
+        .arg("--tls-key").arg(&pki.server_key)
+        .arg("--tls-client-ca").arg(&pki.ca)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr, "--tls-server-name", "localhost"])
+            .arg("--tls-ca").arg(&pki.ca)
+            .arg("--tls-cert").arg(&pki.client_cert)
+            .arg("--tls-key").arg(&pki.client_key)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
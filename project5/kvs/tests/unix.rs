@@ -0,0 +1,76 @@
+use assert_cmd::prelude::*;
+use kvs::{Client, MemEngine, Result, Server};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+use std::sync::{Arc, atomic::AtomicBool};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+
+#[tokio::test]
+async fn unix_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    // left behind by a server that was killed
+    drop(std::os::unix::net::UnixListener::bind(&path)?);
+
+    let server = Server::new(MemEngine::new(), Arc::new(AtomicBool::new(false)))?;
+    let (stop, rx) = oneshot::channel();
+    let handle = {
+        let mut server = server.clone();
+        let path = path.clone();
+        tokio::spawn(async move { server.run_unix(path, 0o600, rx).await })
+    };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+    let client = Client::connect_unix(&path).await?;
+    client.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+
+    // a socket with a server behind it is not taken over
+    let (_stop, second_rx) = oneshot::channel();
+    assert!(server.clone().run_unix(&path, 0o600, second_rx).await.is_err());
+    assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+
+    stop.send(()).unwrap();
+    handle.await.unwrap()?;
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+#[test]
+fn unix_cli() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let addr = "127.0.0.1:4127";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr, "--no-tcp", "--unix-mode", "600"])
+        .arg("--unix").arg(&path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).arg("--unix").arg(&path).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["get", "key1"]).assert().success().stdout("value1\n");
+    // nothing listens on TCP
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}